@@ -0,0 +1,253 @@
+//Static checker - finds problems that would otherwise only show up when the interpreter reaches them
+use crate::infer::{self, Analysis};
+use crate::parse::{self, Diagnostic, Node, NodeKind};
+use crate::{Lexer, Operator};
+use std::collections::{HashMap, HashSet};
+
+pub fn check(lex: &mut Lexer) -> Analysis {
+    let program = parse::parse(lex);
+    let mut diagnostics = program.diagnostics;
+
+    //Scoping is dynamic, so a name is known if it is assigned or defined anywhere in the file.
+    //In lexical mode names are resolved properly instead
+    let mut names = HashSet::<String>::new();
+    collect_names(&program.body, &mut names);
+    let names = match lex.lexical {
+        true => {
+            diagnostics.append(&mut resolve(&program.body));
+            None
+        }
+        false => Some(&names),
+    };
+    check_body(
+        &program.body,
+        names,
+        &mut Vec::new(),
+        false,
+        &mut diagnostics,
+    );
+
+    //Top-level constants are visible in every scope, so no other binding can take their name
+    let mut consts = HashMap::<&str, usize>::new();
+    for node in &program.body {
+        if let NodeKind::Operator(Operator::Constant(s)) = &node.kind {
+            consts.entry(s).or_insert(node.pos);
+        }
+    }
+    check_constants(&program.body, &consts, lex, &mut diagnostics);
+
+    let mut analysis = infer::analyse(&program.body);
+    diagnostics.append(&mut analysis.diagnostics);
+    diagnostics.sort_by_key(|d| d.pos);
+    analysis.diagnostics = diagnostics;
+    analysis
+}
+
+fn collect_names(body: &[Node], names: &mut HashSet<String>) {
+    for node in body {
+        match &node.kind {
+            NodeKind::Operator(
+                Operator::Assign(s)
+                | Operator::Constant(s)
+                | Operator::AssignOuter(s)
+                | Operator::AssignGlobal(s),
+            ) => {
+                names.insert(s.clone());
+            }
+            NodeKind::Define(s, _) | NodeKind::Count(s, _) | NodeKind::Each(s, _) => {
+                names.insert(s.clone());
+            }
+            _ => (),
+        }
+
+        for block in node.kind.blocks() {
+            collect_names(&block.body, names);
+        }
+    }
+}
+
+//Lexical mode: code sees the names bound in its own function, or at the top level if it is not
+//in one, along with the global names and the functions
+pub fn resolve(body: &[Node]) -> Vec<Diagnostic> {
+    let mut globals = HashSet::<&str>::new();
+    collect_globals(body, true, &mut globals);
+
+    let mut diagnostics = Vec::new();
+    resolve_function(body, &globals, &mut diagnostics);
+    diagnostics.sort_by_key(|d| d.pos);
+    diagnostics
+}
+
+//Names bound at the top level, with '=^^' anywhere, and the functions
+fn collect_globals<'a>(body: &'a [Node], top: bool, globals: &mut HashSet<&'a str>) {
+    for node in body {
+        match &node.kind {
+            NodeKind::Operator(Operator::AssignGlobal(s)) | NodeKind::Define(s, _) => {
+                globals.insert(s);
+            }
+            NodeKind::Operator(Operator::Assign(s) | Operator::Constant(s))
+            | NodeKind::Count(s, _)
+            | NodeKind::Each(s, _)
+                if top =>
+            {
+                globals.insert(s);
+            }
+            _ => (),
+        }
+
+        let top = top && !matches!(node.kind, NodeKind::Define(..));
+        for block in node.kind.blocks() {
+            collect_globals(&block.body, top, globals);
+        }
+    }
+}
+
+//Names bound in the function's own body, leaving out the functions defined inside it
+fn collect_locals<'a>(body: &'a [Node], locals: &mut HashSet<&'a str>) {
+    for node in body {
+        match &node.kind {
+            NodeKind::Define(..) => continue,
+            NodeKind::Operator(Operator::Assign(s) | Operator::Constant(s))
+            | NodeKind::Count(s, _)
+            | NodeKind::Each(s, _) => {
+                locals.insert(s);
+            }
+            _ => (),
+        }
+
+        for block in node.kind.blocks() {
+            collect_locals(&block.body, locals);
+        }
+    }
+}
+
+fn resolve_function(body: &[Node], globals: &HashSet<&str>, diagnostics: &mut Vec<Diagnostic>) {
+    let mut locals = HashSet::<&str>::new();
+    collect_locals(body, &mut locals);
+    resolve_body(body, &locals, globals, diagnostics);
+}
+
+fn resolve_body(
+    body: &[Node],
+    locals: &HashSet<&str>,
+    globals: &HashSet<&str>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for node in body {
+        let known = |s: &str| locals.contains(s) || globals.contains(s);
+        match &node.kind {
+            NodeKind::Operator(Operator::Access(s)) if !known(s) => diagnostics.push(Diagnostic {
+                pos: node.pos,
+                msg: format!("Unknown variable '{}'!", s),
+            }),
+            NodeKind::Operator(Operator::AssignOuter(s)) if !known(s) => {
+                diagnostics.push(Diagnostic {
+                    pos: node.pos,
+                    msg: format!("Cannot assign to unknown variable '{}'!", s),
+                })
+            }
+            NodeKind::Define(_, b) => {
+                resolve_function(&b.body, globals, diagnostics);
+                continue;
+            }
+            _ => (),
+        }
+
+        for block in node.kind.blocks() {
+            resolve_body(&block.body, locals, globals, diagnostics);
+        }
+    }
+}
+
+fn check_constants(
+    body: &[Node],
+    consts: &HashMap<&str, usize>,
+    lex: &Lexer,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for node in body {
+        if let NodeKind::Operator(
+            Operator::Assign(s)
+            | Operator::Constant(s)
+            | Operator::AssignOuter(s)
+            | Operator::AssignGlobal(s),
+        )
+        | NodeKind::Count(s, _)
+        | NodeKind::Each(s, _) = &node.kind
+        {
+            match consts.get(s.as_str()) {
+                Some(&pos) if pos != node.pos => {
+                    let (line, col) = lex.location(pos);
+                    diagnostics.push(Diagnostic {
+                        pos: node.pos,
+                        msg: format!(
+                            "Cannot assign to constant '{}', defined at {}:{}!",
+                            s, line, col
+                        ),
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        for block in node.kind.blocks() {
+            check_constants(&block.body, consts, lex, diagnostics);
+        }
+    }
+}
+
+//`loops` are the labels of the loops around this body, innermost last, and `function`
+//is true inside a definition, where a '$' outside any loop returns from it
+fn check_body<'a>(
+    body: &'a [Node],
+    names: Option<&HashSet<String>>, //every name in the file, unless names are resolved lexically
+    loops: &mut Vec<Option<&'a str>>,
+    function: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut label = None;
+    for node in body {
+        let mut error = |msg: String| diagnostics.push(Diagnostic { pos: node.pos, msg });
+        match &node.kind {
+            NodeKind::Operator(Operator::Access(s)) if names.is_some_and(|n| !n.contains(s)) => {
+                error(format!("Unknown variable '{}'!", s))
+            }
+            NodeKind::Break(None) if loops.is_empty() && !function => {
+                error("Can only break from a loop!".to_string())
+            }
+            NodeKind::Return if !function => error("Can only return from a function!".to_string()),
+            NodeKind::Continue(None) if loops.is_empty() => {
+                error("Can only continue inside a loop!".to_string())
+            }
+            NodeKind::Break(Some(l)) | NodeKind::Continue(Some(l))
+                if !loops.contains(&Some(l.as_str())) =>
+            {
+                error(format!("No enclosing loop labelled '{}'!", l))
+            }
+            _ => (),
+        }
+
+        match &node.kind {
+            NodeKind::Label(l) => {
+                label = Some(l.as_str());
+                continue;
+            }
+            NodeKind::Define(_, b) => {
+                check_body(&b.body, names, &mut Vec::new(), true, diagnostics)
+            }
+            NodeKind::Loop(_) | NodeKind::Count(..) | NodeKind::Each(..) | NodeKind::While(..) => {
+                loops.push(label);
+                for block in node.kind.blocks() {
+                    check_body(&block.body, names, loops, function, diagnostics);
+                }
+                loops.pop();
+            }
+            kind => {
+                for block in kind.blocks() {
+                    check_body(&block.body, names, loops, function, diagnostics);
+                }
+            }
+        }
+        label = None;
+    }
+}
@@ -0,0 +1,427 @@
+//Transpiler - turns a program into a self-contained C file with the same output as the interpreter
+use crate::parse::{Block, Case, Node, NodeKind, Program};
+use crate::{Lexer, Operand, Operator};
+use std::collections::HashMap;
+
+const RUNTIME: &str = include_str!("runtime.c");
+
+struct Emitter<'a> {
+    lex: &'a Lexer, //for the locations in messages
+    out: String,
+    indent: usize,
+    defines: Vec<&'a Block>,
+    funcs: HashMap<&'a str, usize>, //index into defines of the definition each name resolves to
+    loops: Vec<Target<'a>>,         //the loops around the code being emitted, innermost last
+    label: Option<&'a str>,         //label for the next loop
+    next_loop: usize,
+    function: bool, //whether a function is being emitted, rather than main
+    tries: usize,   //'!?' blocks around the code being emitted, within the function
+    scopes: usize,  //block scopes around the code being emitted, within the function
+}
+
+//A loop that '$' and '^' can leave or restart. Labelled ones do so through a goto, and the
+//C labels are only emitted when something jumps to them
+struct Target<'a> {
+    label: Option<&'a str>,
+    tries: usize,  //the '!?' blocks around the loop
+    scopes: usize, //the block scopes around the loop
+    id: usize,
+    breaks: bool,
+    continues: bool,
+}
+
+//Escape a string as a C string literal. Octal escapes are used as they cannot run into the next character
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => out += "\\\"",
+            b'\\' => out += "\\\\",
+            b'?' => out += "\\?", //avoid trigraphs
+            0x20..=0x7e => out.push(b as char),
+            _ => out += &format!("\\{:03o}", b),
+        }
+    }
+
+    out + "\""
+}
+
+//A C expression making a runtime value of the literal
+fn c_value(o: &Operand) -> String {
+    match o {
+        Operand::Int(i) => format!("aq_int({})", i),
+        Operand::Bool(b) => format!("aq_bool({})", *b as i32),
+        Operand::String(s) => format!("aq_str({}, {})", c_string(s), s.len()),
+    }
+}
+
+pub fn emit_c(program: &Program, lex: &Lexer) -> String {
+    let mut emitter = Emitter {
+        lex,
+        out: String::new(),
+        indent: 0,
+        defines: Vec::new(),
+        funcs: HashMap::new(),
+        loops: Vec::new(),
+        label: None,
+        next_loop: 0,
+        function: false,
+        tries: 0,
+        scopes: 0,
+    };
+    emitter.collect(&program.body);
+
+    emitter.out += RUNTIME;
+    emitter.out += "\n/* Program */\n";
+    for i in 0..emitter.defines.len() {
+        emitter.out += &format!("AQ_FN void aq_fn_{}(void);\n", i);
+    }
+
+    emitter.function = true;
+    for i in 0..emitter.defines.len() {
+        emitter.out += &format!("\nAQ_FN void aq_fn_{}(void) {{\n", i);
+        emitter.indent = 1;
+        let block = emitter.defines[i];
+        emitter.body(&block.body, Some(false));
+        //Leaving through the closing brace destroys the function's scope
+        emitter.line("aq_destroy_scope();");
+        emitter.out += "}\n";
+    }
+
+    emitter.function = false;
+    emitter.out += "\nint main(void) {\n";
+    emitter.indent = 1;
+    emitter.line("aq_new_scope();");
+    if lex.lexical {
+        emitter.line("aq_lexical = 1;");
+    }
+    if lex.block_scopes {
+        emitter.line("aq_block_scopes = 1;");
+    }
+    emitter.body(&program.body, None);
+    emitter.line("return 0;");
+    emitter.out += "}\n";
+
+    emitter.out
+}
+
+impl<'a> Emitter<'a> {
+    //Find every function definition, wherever it is nested, as register_functions does
+    fn collect(&mut self, body: &'a [Node]) {
+        for node in body {
+            if let NodeKind::Define(name, block) = &node.kind {
+                self.funcs.insert(name, self.defines.len());
+                self.defines.push(block);
+            }
+
+            for block in node.kind.blocks() {
+                self.collect(&block.body);
+            }
+        }
+    }
+
+    fn line(&mut self, s: &str) {
+        self.out += &"    ".repeat(self.indent);
+        self.out += s;
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, head: &str, body: &'a [Node], in_loop: Option<bool>) {
+        match head {
+            "" => self.line("{"),
+            _ => self.line(&format!("{} {{", head)),
+        }
+        self.indent += 1;
+        self.scoped(body, in_loop);
+        self.indent -= 1;
+    }
+
+    //The body of a block, in a scope of its own in block-scopes mode
+    fn scoped(&mut self, body: &'a [Node], in_loop: Option<bool>) {
+        if !self.lex.block_scopes {
+            return self.body(body, in_loop);
+        }
+        self.line("aq_new_block_scope();");
+        self.scopes += 1;
+        self.body(body, in_loop);
+        self.scopes -= 1;
+        self.line("aq_destroy_scope();");
+    }
+
+    //`in_loop` is None at the top level, otherwise whether '$' leaves a loop (true) or the function (false)
+    fn body(&mut self, body: &'a [Node], in_loop: Option<bool>) {
+        for node in body {
+            match &node.kind {
+                NodeKind::Operand(o) => self.line(&format!("aq_push({});", c_value(o))),
+                NodeKind::Operator(o) => self.operator(node.pos, o),
+                NodeKind::Block(b) => {
+                    self.block("", &b.body, in_loop);
+                    self.line("}");
+                }
+                NodeKind::Loop(b) => {
+                    self.start_loop("for (;;) {");
+                    self.scoped(&b.body, Some(true));
+                    self.end_loop();
+                }
+                //The counters are suffixed with the indent so that nested loops do not shadow each other
+                NodeKind::Count(name, b) => {
+                    let n = self.indent;
+                    self.line("{");
+                    self.indent += 1;
+                    self.line(&format!(
+                        "int32_t aq_n{} = aq_pop_int(\"Counted loop requires an Int!\");",
+                        n
+                    ));
+                    self.start_loop(&format!(
+                        "for (int32_t aq_i{0} = 0; aq_i{0} < aq_n{0}; aq_i{0}++) {{",
+                        n
+                    ));
+                    self.line(&format!("aq_push(aq_int(aq_i{}));", n));
+                    self.line(&format!("aq_assign({});", c_string(name)));
+                    self.scoped(&b.body, Some(true));
+                    self.end_loop();
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                NodeKind::Each(name, b) => {
+                    let n = self.indent;
+                    self.line("{");
+                    self.indent += 1;
+                    self.line(&format!(
+                        "aq_value aq_s{} = aq_pop_str(\"For-each loop requires a String!\");",
+                        n
+                    ));
+                    self.start_loop(&format!(
+                        "for (size_t aq_i{0} = 0; aq_i{0} < aq_s{0}.len;) {{",
+                        n
+                    ));
+                    self.line(&format!("aq_push(aq_next_char(aq_s{0}, &aq_i{0}));", n));
+                    self.line(&format!("aq_assign({});", c_string(name)));
+                    self.scoped(&b.body, Some(true));
+                    self.end_loop();
+                    self.line(&format!("aq_free(aq_s{});", n));
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                NodeKind::While(c, b) => {
+                    self.start_loop("for (;;) {");
+                    self.scoped(&c.body, Some(true));
+                    self.line("if (!aq_while_cond()) break;");
+                    self.scoped(&b.body, Some(true));
+                    self.end_loop();
+                }
+                NodeKind::Cond(b, else_ifs, e) => {
+                    self.block("if (aq_cond())", &b.body, in_loop);
+                    self.else_branch(else_ifs, e, in_loop);
+                    self.line("}");
+                }
+                NodeKind::Match(cases) => self.match_cases(cases, in_loop),
+                //Definitions are emitted as separate functions
+                NodeKind::Define(..) => (),
+                NodeKind::Label(l) => {
+                    self.label = Some(l);
+                    continue;
+                }
+                NodeKind::Try(b, h) => {
+                    self.line("if (!setjmp(aq_try_push()->env)) {");
+                    self.indent += 1;
+                    self.tries += 1;
+                    self.scoped(&b.body, in_loop);
+                    self.tries -= 1;
+                    self.line("aq_try_pop();");
+                    self.indent -= 1;
+                    self.line("} else {");
+                    self.indent += 1;
+                    self.line("aq_catch();");
+                    self.scoped(&h.body, in_loop);
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                NodeKind::Break(None) => match in_loop {
+                    Some(true) => {
+                        self.leave_loop_blocks();
+                        self.line("break;");
+                    }
+                    Some(false) => {
+                        self.leave_blocks(0, 0);
+                        self.line("aq_destroy_scope(); return;");
+                    }
+                    None => self.line("aq_error(\"Can only break from a loop!\");"),
+                },
+                NodeKind::Return if self.function => {
+                    self.leave_blocks(0, 0);
+                    self.line("aq_destroy_scope(); return;");
+                }
+                NodeKind::Return => self.line("aq_error(\"Can only return from a function!\");"),
+                NodeKind::Continue(None) => match in_loop {
+                    Some(true) => {
+                        self.leave_loop_blocks();
+                        self.line("continue;");
+                    }
+                    _ => self.line("aq_error(\"Can only continue inside a loop!\");"),
+                },
+                NodeKind::Break(Some(l)) | NodeKind::Continue(Some(l)) => {
+                    let continues = matches!(node.kind, NodeKind::Continue(_));
+                    match self
+                        .loops
+                        .iter_mut()
+                        .rev()
+                        .find(|t| t.label == Some(l.as_str()))
+                    {
+                        Some(t) => {
+                            let s = match continues {
+                                true => format!("goto aq_continue_{};", t.id),
+                                false => format!("goto aq_break_{};", t.id),
+                            };
+                            t.continues |= continues;
+                            t.breaks |= !continues;
+                            let (tries, scopes) = (t.tries, t.scopes);
+                            self.leave_blocks(tries, scopes);
+                            self.line(&s);
+                        }
+                        None => self.line(&format!(
+                            "aq_error({});",
+                            c_string(&format!("No enclosing loop labelled '{}'!", l))
+                        )),
+                    }
+                }
+            }
+            self.label = None;
+        }
+    }
+
+    //Else-ifs nest inside the else of the branch before them, as their conditions run there
+    fn else_branch(
+        &mut self,
+        else_ifs: &'a [(Block, Block)],
+        e: &'a Option<Block>,
+        in_loop: Option<bool>,
+    ) {
+        match else_ifs.split_first() {
+            Some(((c, b), rest)) => {
+                self.line("} else {");
+                self.indent += 1;
+                self.scoped(&c.body, in_loop);
+                self.block("if (aq_cond())", &b.body, in_loop);
+                self.else_branch(rest, e, in_loop);
+                self.line("}");
+                self.indent -= 1;
+            }
+            None => {
+                if let Some(e) = e {
+                    self.line("} else {");
+                    self.indent += 1;
+                    self.scoped(&e.body, in_loop);
+                    self.indent -= 1;
+                }
+            }
+        }
+    }
+
+    fn match_cases(&mut self, cases: &'a [Case], in_loop: Option<bool>) {
+        let n = self.indent;
+        self.line("{");
+        self.indent += 1;
+        self.line(&format!("aq_value aq_m{} = aq_pop();", n));
+        for (i, case) in cases.iter().enumerate() {
+            let head = match (&case.value, i) {
+                (Some(o), 0) => format!("if (aq_matches(aq_m{}, {}))", n, c_value(o)),
+                (Some(o), _) => format!("}} else if (aq_matches(aq_m{}, {}))", n, c_value(o)),
+                (None, 0) => String::new(),
+                (None, _) => "} else".to_string(),
+            };
+            self.block(&head, &case.block.body, in_loop);
+        }
+        if !cases.is_empty() {
+            self.line("}");
+        }
+        self.line(&format!("aq_free(aq_m{});", n));
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    //Jumping out of a '!?' has to drop its handler, and out of a block its scope, down to the
+    //`tries` and `scopes` of the jump's target
+    fn leave_blocks(&mut self, tries: usize, scopes: usize) {
+        if self.tries > tries {
+            self.line(&format!("aq_try_drop({});", self.tries - tries));
+        }
+        if self.scopes > scopes {
+            self.line(&format!("aq_destroy_scopes({});", self.scopes - scopes));
+        }
+    }
+
+    //For an unlabelled '$' or '^', which go to the innermost loop
+    fn leave_loop_blocks(&mut self) {
+        let (tries, scopes) = self.loops.last().map_or((0, 0), |t| (t.tries, t.scopes));
+        self.leave_blocks(tries, scopes);
+    }
+
+    //Open the C loop for an aqua one, taking the pending label
+    fn start_loop(&mut self, head: &str) {
+        self.loops.push(Target {
+            label: self.label.take(),
+            tries: self.tries,
+            scopes: self.scopes,
+            id: self.next_loop,
+            breaks: false,
+            continues: false,
+        });
+        self.next_loop += 1;
+        self.line(head);
+        self.indent += 1;
+    }
+
+    fn end_loop(&mut self) {
+        let target = self.loops.pop().unwrap();
+        if target.continues {
+            self.line(&format!("aq_continue_{}:;", target.id));
+        }
+        self.indent -= 1;
+        self.line("}");
+        if target.breaks {
+            self.line(&format!("aq_break_{}:;", target.id));
+        }
+    }
+
+    fn operator(&mut self, pos: usize, o: &Operator) {
+        let s = match o {
+            Operator::Add => "aq_add();".to_string(),
+            Operator::Sub => "aq_sub();".to_string(),
+            Operator::Mul => "aq_mul();".to_string(),
+            Operator::Div => "aq_div();".to_string(),
+            Operator::Print => "aq_print();".to_string(),
+            Operator::Equal => "aq_equal(0);".to_string(),
+            Operator::NotEqual => "aq_equal(1);".to_string(),
+            Operator::LessThan => "aq_less_than();".to_string(),
+            Operator::GreaterThan => "aq_greater_than();".to_string(),
+            //A '?' without a block is rejected by the parser
+            Operator::Cond => "aq_cond();".to_string(),
+            Operator::Pop => "aq_silent_pop();".to_string(),
+            Operator::Clear => "aq_clear();".to_string(),
+            Operator::Assign(name) => format!("aq_assign({});", c_string(name)),
+            Operator::AssignOuter(name) => format!("aq_assign_outer({});", c_string(name)),
+            Operator::AssignGlobal(name) => format!("aq_assign_global({});", c_string(name)),
+            Operator::Constant(name) => {
+                let (line, col) = self.lex.location(pos);
+                let at = format!("{}:{}", line, col);
+                format!("aq_constant({}, {});", c_string(name), c_string(&at))
+            }
+            Operator::Access(name) => match self.funcs.get(name.as_str()) {
+                Some(i) => format!(
+                    "if (!aq_access({})) {{ aq_new_scope(); aq_fn_{}(); }}",
+                    c_string(name),
+                    i
+                ),
+                None => format!("if (!aq_access({0})) aq_unknown({0});", c_string(name)),
+            },
+            Operator::Index => "aq_index();".to_string(),
+            Operator::Input => "aq_input();".to_string(),
+            Operator::Throw => "aq_throw();".to_string(),
+            Operator::Assert => "aq_assert();".to_string(),
+            Operator::AssertEq => "aq_assert_eq();".to_string(),
+        };
+
+        self.line(&s);
+    }
+}
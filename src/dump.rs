@@ -0,0 +1,365 @@
+//Token and syntax tree dumps, to show how the lexer and parser read a file
+use crate::json::Json;
+use crate::parse::{self, Block, Diagnostic, Node, NodeKind};
+use crate::{Glyph, Lexer, Named, Op, Operand, Operator};
+
+fn operand_kind(o: &Operand) -> &'static str {
+    match o {
+        Operand::Int(_) => "Int",
+        Operand::Bool(_) => "Bool",
+        Operand::String(_) => "String",
+    }
+}
+
+fn operator_kind(o: &Operator) -> &'static str {
+    match o {
+        Operator::Add => "Add",
+        Operator::Sub => "Sub",
+        Operator::Mul => "Mul",
+        Operator::Div => "Div",
+        Operator::Print => "Print",
+        Operator::Equal => "Equal",
+        Operator::NotEqual => "NotEqual",
+        Operator::LessThan => "LessThan",
+        Operator::GreaterThan => "GreaterThan",
+        Operator::Cond => "Cond",
+        Operator::Pop => "Pop",
+        Operator::Clear => "Clear",
+        Operator::Assign(_) => "Assign",
+        Operator::Constant(_) => "Constant",
+        Operator::AssignOuter(_) => "AssignOuter",
+        Operator::AssignGlobal(_) => "AssignGlobal",
+        Operator::Access(_) => "Access",
+        Operator::Index => "Index",
+        Operator::Input => "Input",
+        Operator::Throw => "Throw",
+        Operator::Assert => "Assert",
+        Operator::AssertEq => "AssertEq",
+    }
+}
+
+//The kind of the token a resolved name came from, as kind() gives it
+pub fn named_kind(named: Named) -> &'static str {
+    match named {
+        Named::Load => "Access",
+        Named::Store => "Assign",
+        Named::Constant => "Constant",
+        Named::AssignOuter => "AssignOuter",
+        Named::AssignGlobal => "AssignGlobal",
+        Named::Count => "Count",
+        Named::Each => "Each",
+    }
+}
+
+pub fn kind(op: &Op) -> &'static str {
+    match op {
+        Op::Operand(o) => operand_kind(o),
+        Op::Operator(o) => operator_kind(o),
+        Op::Glyph(g) => match g {
+            Glyph::OpenSquiggle => "OpenSquiggle",
+            Glyph::CloseSquiggle => "CloseSquiggle",
+            Glyph::Loop => "Loop",
+            Glyph::Count(_) => "Count",
+            Glyph::Each(_) => "Each",
+            Glyph::While => "While",
+            Glyph::Label(_) => "Label",
+            Glyph::Break(_) => "Break",
+            Glyph::Continue(_) => "Continue",
+            Glyph::Return => "Return",
+            Glyph::Else => "Else",
+            Glyph::ElseIf => "ElseIf",
+            Glyph::Match => "Match",
+            Glyph::Try => "Try",
+            Glyph::Define => "Define",
+        },
+    }
+}
+
+fn operand_json(o: &Operand) -> Json {
+    match o {
+        Operand::Int(i) => Json::from(*i),
+        Operand::Bool(b) => Json::from(*b),
+        Operand::String(s) => Json::from(&s[..]),
+    }
+}
+
+fn location_json(lex: &Lexer, pos: usize) -> Json {
+    let (line, col) = lex.location(pos);
+    Json::object(vec![("line", Json::from(line)), ("col", Json::from(col))])
+}
+
+pub fn tokens(lex: &mut Lexer, json: bool) -> String {
+    let mut out = String::new();
+    let mut list = Vec::<Json>::new();
+
+    for token in parse::tokens(lex) {
+        let text: String = lex.chars[token.start..=token.end].iter().collect();
+        let (line, col) = lex.location(token.start);
+
+        if json {
+            let mut fields = vec![
+                ("kind", Json::from(kind(&token.op))),
+                ("text", Json::from(text)),
+                ("line", Json::from(line)),
+                ("col", Json::from(col)),
+            ];
+            match &token.op {
+                Op::Operand(o) => fields.push(("value", operand_json(o))),
+                Op::Operator(
+                    Operator::Assign(s)
+                    | Operator::Constant(s)
+                    | Operator::AssignOuter(s)
+                    | Operator::AssignGlobal(s)
+                    | Operator::Access(s),
+                ) => fields.push(("name", Json::from(s.as_str()))),
+                Op::Glyph(Glyph::Count(s) | Glyph::Each(s) | Glyph::Label(s)) => {
+                    fields.push(("name", Json::from(s.as_str())))
+                }
+                Op::Glyph(Glyph::Break(Some(s)) | Glyph::Continue(Some(s))) => {
+                    fields.push(("label", Json::from(s.as_str())))
+                }
+                _ => (),
+            }
+            list.push(Json::object(fields));
+        } else {
+            out += &format!("{}:{} {} {:?}\n", line, col, kind(&token.op), text);
+        }
+    }
+
+    if json {
+        out = Json::Array(list).to_string() + "\n";
+    }
+    out
+}
+
+//Returns the dump, along with any problems the parser found
+pub fn ast(lex: &mut Lexer, json: bool) -> (String, Vec<Diagnostic>) {
+    let program = parse::parse(lex);
+
+    let out = if json {
+        let diagnostics = program
+            .diagnostics
+            .iter()
+            .map(|d| {
+                let (line, col) = lex.location(d.pos);
+                Json::object(vec![
+                    ("line", Json::from(line)),
+                    ("col", Json::from(col)),
+                    ("message", Json::from(d.msg.as_str())),
+                ])
+            })
+            .collect();
+        let root = Json::object(vec![
+            ("body", body_json(lex, &program.body)),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]);
+        root.to_string() + "\n"
+    } else {
+        let mut out = String::new();
+        body_text(lex, &program.body, 0, &mut out);
+        out
+    };
+
+    (out, program.diagnostics)
+}
+
+fn body_text(lex: &Lexer, body: &[Node], depth: usize, out: &mut String) {
+    for node in body {
+        let (line, col) = lex.location(node.pos);
+        let label = match &node.kind {
+            NodeKind::Operand(o) => format!("Operand {:?}", o),
+            NodeKind::Operator(o) => format!("Operator {:?}", o),
+            NodeKind::Block(_) => "Block".to_string(),
+            NodeKind::Loop(_) => "Loop".to_string(),
+            NodeKind::Count(name, _) => format!("Count {}", name),
+            NodeKind::Each(name, _) => format!("Each {}", name),
+            NodeKind::While(..) => "While".to_string(),
+            NodeKind::Cond(..) => "Cond".to_string(),
+            NodeKind::Match(_) => "Match".to_string(),
+            NodeKind::Try(..) => "Try".to_string(),
+            NodeKind::Define(name, _) => format!("Define {}", name),
+            NodeKind::Label(name) => format!("Label {}", name),
+            NodeKind::Break(None) => "Break".to_string(),
+            NodeKind::Break(Some(label)) => format!("Break {}", label),
+            NodeKind::Continue(None) => "Continue".to_string(),
+            NodeKind::Continue(Some(label)) => format!("Continue {}", label),
+            NodeKind::Return => "Return".to_string(),
+        };
+        *out += &format!("{}{}:{} {}\n", "    ".repeat(depth), line, col, label);
+
+        match &node.kind {
+            NodeKind::Cond(b, else_ifs, e) => {
+                body_text(lex, &b.body, depth + 1, out);
+                for (c, b) in else_ifs {
+                    let (line, col) = lex.location(c.open);
+                    *out += &format!("{}{}:{} ElseIf\n", "    ".repeat(depth), line, col);
+                    body_text(lex, &c.body, depth + 1, out);
+                    let (line, col) = lex.location(b.open);
+                    *out += &format!("{}{}:{} Then\n", "    ".repeat(depth), line, col);
+                    body_text(lex, &b.body, depth + 1, out);
+                }
+                if let Some(e) = e {
+                    let (line, col) = lex.location(e.open);
+                    *out += &format!("{}{}:{} Else\n", "    ".repeat(depth), line, col);
+                    body_text(lex, &e.body, depth + 1, out);
+                }
+            }
+            NodeKind::Match(cases) => {
+                for case in cases {
+                    let (line, col) = lex.location(case.pos);
+                    let label = match &case.value {
+                        Some(o) => format!("Case {:?}", o),
+                        None => "Default".to_string(),
+                    };
+                    *out += &format!("{}{}:{} {}\n", "    ".repeat(depth + 1), line, col, label);
+                    body_text(lex, &case.block.body, depth + 2, out);
+                }
+            }
+            NodeKind::While(c, b) => {
+                body_text(lex, &c.body, depth + 1, out);
+                let (line, col) = lex.location(b.open);
+                *out += &format!("{}{}:{} Do\n", "    ".repeat(depth), line, col);
+                body_text(lex, &b.body, depth + 1, out);
+            }
+            NodeKind::Try(b, h) => {
+                body_text(lex, &b.body, depth + 1, out);
+                let (line, col) = lex.location(h.open);
+                *out += &format!("{}{}:{} Catch\n", "    ".repeat(depth), line, col);
+                body_text(lex, &h.body, depth + 1, out);
+            }
+            kind => {
+                for block in kind.blocks() {
+                    body_text(lex, &block.body, depth + 1, out);
+                }
+            }
+        }
+    }
+}
+
+fn block_json(lex: &Lexer, block: &Block) -> Json {
+    Json::object(vec![
+        ("open", location_json(lex, block.open)),
+        (
+            "close",
+            match block.close {
+                Some(pos) => location_json(lex, pos),
+                None => Json::Null,
+            },
+        ),
+        ("body", body_json(lex, &block.body)),
+    ])
+}
+
+fn body_json(lex: &Lexer, body: &[Node]) -> Json {
+    let mut list = Vec::<Json>::new();
+    for node in body {
+        let (line, col) = lex.location(node.pos);
+        let kind = match &node.kind {
+            NodeKind::Operand(_) => "Operand",
+            NodeKind::Operator(_) => "Operator",
+            NodeKind::Block(_) => "Block",
+            NodeKind::Loop(_) => "Loop",
+            NodeKind::Count(..) => "Count",
+            NodeKind::Each(..) => "Each",
+            NodeKind::While(..) => "While",
+            NodeKind::Cond(..) => "Cond",
+            NodeKind::Match(_) => "Match",
+            NodeKind::Try(..) => "Try",
+            NodeKind::Define(..) => "Define",
+            NodeKind::Label(_) => "Label",
+            NodeKind::Break(_) => "Break",
+            NodeKind::Continue(_) => "Continue",
+            NodeKind::Return => "Return",
+        };
+        let mut fields = vec![
+            ("kind", Json::from(kind)),
+            ("line", Json::from(line)),
+            ("col", Json::from(col)),
+        ];
+
+        match &node.kind {
+            NodeKind::Operand(o) => {
+                fields.push(("type", Json::from(operand_kind(o))));
+                fields.push(("value", operand_json(o)));
+            }
+            NodeKind::Operator(o) => {
+                fields.push(("op", Json::from(operator_kind(o))));
+                if let Operator::Assign(s)
+                | Operator::Constant(s)
+                | Operator::AssignOuter(s)
+                | Operator::AssignGlobal(s)
+                | Operator::Access(s) = o
+                {
+                    fields.push(("name", Json::from(s.as_str())));
+                }
+            }
+            NodeKind::Block(b) | NodeKind::Loop(b) => fields.push(("block", block_json(lex, b))),
+            NodeKind::Cond(b, else_ifs, e) => {
+                fields.push(("then", block_json(lex, b)));
+                let else_ifs = else_ifs
+                    .iter()
+                    .map(|(c, b)| {
+                        Json::object(vec![
+                            ("cond", block_json(lex, c)),
+                            ("block", block_json(lex, b)),
+                        ])
+                    })
+                    .collect();
+                fields.push(("else_ifs", Json::Array(else_ifs)));
+                fields.push((
+                    "else",
+                    match e {
+                        Some(e) => block_json(lex, e),
+                        None => Json::Null,
+                    },
+                ));
+            }
+            NodeKind::Define(name, b) | NodeKind::Count(name, b) | NodeKind::Each(name, b) => {
+                fields.push(("name", Json::from(name.as_str())));
+                fields.push(("block", block_json(lex, b)));
+            }
+            NodeKind::Match(cases) => {
+                let cases = cases
+                    .iter()
+                    .map(|case| {
+                        let (line, col) = lex.location(case.pos);
+                        Json::object(vec![
+                            ("line", Json::from(line)),
+                            ("col", Json::from(col)),
+                            (
+                                "value",
+                                match &case.value {
+                                    Some(o) => operand_json(o),
+                                    None => Json::Null,
+                                },
+                            ),
+                            ("block", block_json(lex, &case.block)),
+                        ])
+                    })
+                    .collect();
+                fields.push(("cases", Json::Array(cases)));
+            }
+            NodeKind::While(c, b) => {
+                fields.push(("cond", block_json(lex, c)));
+                fields.push(("block", block_json(lex, b)));
+            }
+            NodeKind::Try(b, h) => {
+                fields.push(("block", block_json(lex, b)));
+                fields.push(("handler", block_json(lex, h)));
+            }
+            NodeKind::Label(name) => fields.push(("name", Json::from(name.as_str()))),
+            NodeKind::Return => (),
+            NodeKind::Break(label) | NodeKind::Continue(label) => fields.push((
+                "label",
+                match label {
+                    Some(l) => Json::from(l.as_str()),
+                    None => Json::Null,
+                },
+            )),
+        }
+
+        list.push(Json::object(fields));
+    }
+
+    Json::Array(list)
+}
@@ -0,0 +1,262 @@
+//Source formatter - re-emits a program with canonical indentation and spacing.
+//Tokens are copied through verbatim and only the whitespace between them changes
+use crate::parse;
+use crate::{Glyph, Lexer, Op, Operator};
+
+const INDENT: &str = "    ";
+
+enum Item {
+    Token(Op),
+    LineComment,
+    BlockComment,
+}
+
+struct Piece {
+    item: Item,
+    text: String,
+    newlines: usize,  //newlines in the source between this and the previous piece
+    multi_line: bool, //for '{', whether the block spans several lines in the source
+}
+
+//Returns the formatted program, or the parse errors that stop it being formatted
+pub fn format(lex: &mut Lexer) -> Result<String, Vec<parse::Diagnostic>> {
+    let program = parse::parse(lex);
+    if !program.diagnostics.is_empty() {
+        return Err(program.diagnostics);
+    }
+
+    let pieces = pieces(lex);
+    let out = layout(&pieces);
+
+    //Formatting must never change what the program means, so if it would the file is left alone
+    let mut check = Lexer::new("\n".to_string() + &out);
+    let (before, after) = (parse::tokens(lex), parse::tokens(&mut check));
+    let changed = before
+        .iter()
+        .zip(&after)
+        .find(|(b, a)| b.op != a.op)
+        .map(|(b, _)| b.start)
+        .or_else(|| (before.len() != after.len()).then(|| lex.chars.len() - 1));
+    if let Some(pos) = changed {
+        return Err(vec![parse::Diagnostic {
+            pos,
+            msg: "Formatting would change the meaning of the program!".to_string(),
+        }]);
+    }
+
+    Ok(out)
+}
+
+//Split the source into tokens and the comments between them
+fn pieces(lex: &mut Lexer) -> Vec<Piece> {
+    let mut pieces = Vec::<Piece>::new();
+    let mut gap_start = 1; //skip the newline added in read_source
+
+    for token in parse::tokens(lex) {
+        let newlines = gap(&lex.chars[gap_start..token.start], &mut pieces);
+        pieces.push(Piece {
+            text: lex.chars[token.start..=token.end].iter().collect(),
+            item: Item::Token(token.op),
+            newlines,
+            multi_line: false,
+        });
+        gap_start = token.end + 1;
+    }
+    gap(&lex.chars[gap_start.min(lex.chars.len())..], &mut pieces);
+
+    let mut open = Vec::<usize>::new();
+    for i in 0..pieces.len() {
+        if is_glyph(pieces.get(i), Glyph::OpenSquiggle) {
+            open.push(i);
+        } else if is_glyph(pieces.get(i), Glyph::CloseSquiggle) {
+            if let Some(o) = open.pop() {
+                pieces[o].multi_line = pieces[o + 1..=i]
+                    .iter()
+                    .any(|p| p.newlines > 0 || p.text.contains('\n'));
+            }
+        }
+    }
+
+    pieces
+}
+
+//Whatever is between two tokens is whitespace and comments. Returns the number of
+//newlines after the last comment, which belong to the next token
+fn gap(chars: &[char], pieces: &mut Vec<Piece>) -> usize {
+    let mut newlines = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let starts = |s: &str| chars[i..].iter().take(s.len()).copied().eq(s.chars());
+        let (item, len) = if starts("//") {
+            let len = chars[i..].iter().take_while(|c| **c != '\n').count();
+            (Item::LineComment, len)
+        } else if starts("/*") {
+            //Same rules as Lexer::multi_line_comment, the '*' of "/*" can also close it
+            let mut len = 2;
+            while i + len < chars.len() && !(chars[i + len - 1] == '*' && chars[i + len] == '/') {
+                len += 1;
+            }
+            (Item::BlockComment, (len + 1).min(chars.len() - i))
+        } else {
+            if chars[i] == '\n' {
+                newlines += 1;
+            }
+            i += 1;
+            continue;
+        };
+
+        let text: String = chars[i..i + len].iter().collect();
+        pieces.push(Piece {
+            text: match item {
+                Item::LineComment => text.trim_end().to_string(),
+                _ => text,
+            },
+            item,
+            newlines,
+            multi_line: false,
+        });
+        newlines = 0;
+        i += len;
+    }
+
+    newlines
+}
+
+fn is_glyph(piece: Option<&Piece>, glyph: Glyph) -> bool {
+    matches!(piece, Some(Piece { item: Item::Token(Op::Glyph(g)), .. }) if *g == glyph)
+}
+
+//Loop glyphs are written right up against their '{'
+fn is_loop(piece: Option<&Piece>) -> bool {
+    matches!(
+        piece,
+        Some(Piece {
+            item: Item::Token(Op::Glyph(
+                Glyph::Loop | Glyph::Count(_) | Glyph::Each(_) | Glyph::While
+            )),
+            ..
+        })
+    )
+}
+
+//The '{' of the innermost block still open just before `at`
+fn opener(pieces: &[Piece], at: usize) -> Option<usize> {
+    let mut depth = 0;
+    for i in (0..at).rev() {
+        if is_glyph(pieces.get(i), Glyph::CloseSquiggle) {
+            depth += 1;
+        } else if is_glyph(pieces.get(i), Glyph::OpenSquiggle) {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+
+    None
+}
+
+//The piece before the '{' that the '}' at `close` ends
+fn before_block(pieces: &[Piece], close: usize) -> Option<&Piece> {
+    match opener(pieces, close) {
+        Some(open) if open > 0 => pieces.get(open - 1),
+        _ => None,
+    }
+}
+
+//Whether the '}' at `close` ends the condition of a while loop or an else-if, or a '!?' block
+fn ends_condition(pieces: &[Piece], close: usize) -> bool {
+    let before = before_block(pieces, close);
+    is_glyph(before, Glyph::While)
+        || is_glyph(before, Glyph::ElseIf)
+        || is_glyph(before, Glyph::Try)
+}
+
+//'{' always stays with the '~', '?', '??', ':', ':?', '!?' or '#name' it belongs to, the block of
+//a while loop or else-if with its condition, a handler with its '!?' block, and ':' or ':?' with
+//its '}'
+fn attached(pieces: &[Piece], i: usize) -> bool {
+    let prev = |n: usize| if i >= n { pieces.get(i - n) } else { None };
+
+    //A chain with else-ifs is laid out as the source has it, and the default case of a match
+    //is a case like any other
+    if is_glyph(pieces.get(i), Glyph::Else) {
+        if i == 0 || !is_glyph(prev(1), Glyph::CloseSquiggle) {
+            return false;
+        }
+        let in_match =
+            opener(pieces, i).is_some_and(|o| o > 0 && is_glyph(pieces.get(o - 1), Glyph::Match));
+        let after_else_if = is_glyph(before_block(pieces, i - 1), Glyph::CloseSquiggle);
+        return !in_match && !after_else_if;
+    }
+    if !is_glyph(pieces.get(i), Glyph::OpenSquiggle) {
+        return false;
+    }
+
+    is_loop(prev(1))
+        || (i > 0 && is_glyph(prev(1), Glyph::CloseSquiggle) && ends_condition(pieces, i - 1))
+        || is_glyph(prev(1), Glyph::Else)
+        || is_glyph(prev(1), Glyph::ElseIf)
+        || is_glyph(prev(1), Glyph::Match)
+        || is_glyph(prev(1), Glyph::Try)
+        || is_glyph(prev(2), Glyph::Define)
+        || matches!(
+            prev(1),
+            Some(Piece {
+                item: Item::Token(Op::Operator(Operator::Cond)),
+                ..
+            })
+        )
+}
+
+fn layout(pieces: &[Piece]) -> String {
+    let mut out = String::new();
+    let mut indent = 0;
+    let mut blocks = Vec::<bool>::new(); //multi_line flag of each open block
+    let mut break_next = false; //the next piece must start a new line
+
+    for (i, piece) in pieces.iter().enumerate() {
+        let inline = blocks.last() == Some(&false);
+        let close = is_glyph(Some(piece), Glyph::CloseSquiggle);
+        let close_multi = close && blocks.pop() == Some(true);
+        if close_multi {
+            indent -= 1;
+        }
+
+        if out.is_empty() {
+        } else if break_next
+            || close_multi
+            || (piece.newlines > 0 && !inline && !attached(pieces, i))
+        {
+            out.push('\n');
+            if piece.newlines > 1
+                && !close_multi
+                && !is_glyph(pieces.get(i - 1), Glyph::OpenSquiggle)
+            {
+                out.push('\n');
+            }
+            out.push_str(&INDENT.repeat(indent));
+        } else if is_glyph(Some(piece), Glyph::OpenSquiggle) && i > 0 && is_loop(pieces.get(i - 1))
+        {
+            //"~{" is written without a space
+        } else if i > 0 && is_glyph(pieces.get(i - 1), Glyph::Define) {
+            //"#name" must not be split, or the name would be lost
+        } else {
+            out.push(' ');
+        }
+
+        out.push_str(&piece.text);
+        break_next = matches!(piece.item, Item::LineComment);
+
+        if is_glyph(Some(piece), Glyph::OpenSquiggle) {
+            blocks.push(piece.multi_line);
+            if piece.multi_line {
+                indent += 1;
+                break_next = true;
+            }
+        }
+    }
+
+    out.push('\n');
+    out
+}
@@ -0,0 +1,212 @@
+//Fuzz tests - feeds random and mutated programs to the lexer, the static tools and the interpreter.
+//None of them may panic, whatever the input
+use crate::{check, format, parse, Interpreter, Lexer};
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::Path;
+
+const PROGRAMS: usize = 3000;
+const STEPS: usize = 2000;
+const DEEP_PROGRAMS: usize = 10;
+const DEPTH: usize = 2000; //far past parse::MAX_DEPTH, and past where a test thread would overflow without it
+
+//Pieces of aqua that random programs are built from
+const FRAGMENTS: &[&str] = &[
+    "0",
+    "1",
+    "7",
+    "2147483647",
+    "-",
+    "+",
+    "*",
+    "/",
+    ".",
+    ",",
+    ";",
+    "_",
+    "==",
+    "!=",
+    "<",
+    ">",
+    "[]",
+    "?",
+    "{",
+    "}",
+    "~",
+    "~i",
+    "~@c",
+    "~?",
+    "$",
+    "$l",
+    "$$",
+    "^",
+    "^l",
+    "@l",
+    ":",
+    ":?",
+    "??",
+    "!",
+    "!?",
+    "assert",
+    "assert-eq",
+    "#",
+    "#f",
+    "f",
+    "=x",
+    "=!x",
+    "=^x",
+    "=^^y",
+    "x",
+    "=y",
+    "y",
+    "true",
+    "false",
+    "\"s\"",
+    "'t'",
+    "\"\\n\"",
+    "\"\\u{41}\"",
+    "r\"\\\"",
+    "\"\"\"",
+    "\"",
+    "'",
+    "\\",
+    "//",
+    "/*",
+    "*/",
+    " ",
+    "\n",
+    "é",
+    "\0",
+];
+
+//What deeply nested programs open their blocks with
+const OPENERS: &[&str] = &[
+    "{",
+    "~{",
+    "true ? {",
+    "!? {",
+    "~? {",
+    "0 ?? { 0 {",
+    "#f {",
+    "3 ~i{",
+];
+
+//xorshift64, so that every run sees the same programs
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+fn random_bytes(rng: &mut Rng) -> String {
+    let bytes: Vec<u8> = (0..rng.below(64)).map(|_| rng.below(256) as u8).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn random_fragments(rng: &mut Rng) -> String {
+    (0..rng.below(40))
+        .map(|_| FRAGMENTS[rng.below(FRAGMENTS.len())].to_string() + " ")
+        .collect()
+}
+
+//Blocks inside blocks, far deeper than any real program, with their closing braces sometimes
+//missing or extra
+fn random_nesting(rng: &mut Rng) -> String {
+    let depth = DEPTH / 2 + rng.below(DEPTH / 2);
+    let mut source = String::new();
+    for _ in 0..depth {
+        source += OPENERS[rng.below(OPENERS.len())];
+        source += " ";
+        //Nothing that could comment out or quote the rest of the program
+        let fragment = FRAGMENTS[rng.below(FRAGMENTS.len())];
+        if !fragment.contains(['/', '"', '\'', '\\', '\0']) {
+            source += fragment;
+            source += " ";
+        }
+    }
+    source += &"} ".repeat(depth + rng.below(3) - 1);
+    source
+}
+
+//Delete, duplicate or replace a few characters of a real program
+fn mutate(rng: &mut Rng, program: &str) -> String {
+    let mut chars: Vec<char> = program.chars().collect();
+    for _ in 0..=rng.below(4) {
+        let at = rng.below(chars.len() + 1);
+        match rng.below(3) {
+            0 if at < chars.len() => {
+                chars.remove(at);
+            }
+            1 if at < chars.len() => chars.insert(at, chars[at]),
+            _ => {
+                let fragment = FRAGMENTS[rng.below(FRAGMENTS.len())];
+                chars.splice(at..at, fragment.chars());
+            }
+        }
+    }
+
+    chars.into_iter().collect()
+}
+
+fn exercise(source: &str) {
+    let data = "\n".to_string() + source;
+
+    let mut lex = Lexer::new(data.clone());
+    parse::tokens(&mut lex);
+    check::check(&mut lex);
+    let _ = format::format(&mut lex);
+
+    let mut interpreter = Interpreter::new(data);
+    interpreter.out = Box::new(io::sink());
+    interpreter.input = Box::new(io::empty());
+    interpreter.limit = Some(STEPS);
+    let _ = interpreter.run();
+}
+
+#[test]
+fn never_panics() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut seeds: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "aq"))
+        .map(|p| fs::read_to_string(p).unwrap())
+        .collect();
+    seeds.sort();
+
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    for i in 0..PROGRAMS {
+        let source = match i % 3 {
+            0 => random_bytes(&mut rng),
+            1 => random_fragments(&mut rng),
+            _ => {
+                let seed = &seeds[rng.below(seeds.len())];
+                mutate(&mut rng, seed)
+            }
+        };
+
+        if panic::catch_unwind(|| exercise(&source)).is_err() {
+            panic!("Panicked on program {:?}", source);
+        }
+    }
+}
+
+#[test]
+fn deep_nesting_never_panics() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..DEEP_PROGRAMS {
+        let source = random_nesting(&mut rng);
+        if panic::catch_unwind(|| exercise(&source)).is_err() {
+            panic!(
+                "Panicked on a program nested {} deep",
+                source.matches('{').count()
+            );
+        }
+    }
+}
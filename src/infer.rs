@@ -0,0 +1,685 @@
+//Stack effect inference - runs the program over types instead of values, so underflows,
+//type errors and unbalanced blocks are found before anything is executed
+use crate::parse::{Block, Diagnostic, Node, NodeKind};
+use crate::{Operand, Operator};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ty {
+    Int,
+    Bool,
+    String,
+    Any,
+}
+
+impl Ty {
+    fn of(o: &Operand) -> Ty {
+        match o {
+            Operand::Int(_) => Ty::Int,
+            Operand::Bool(_) => Ty::Bool,
+            Operand::String(_) => Ty::String,
+        }
+    }
+
+    fn join(self, other: Ty) -> Ty {
+        if self == other {
+            self
+        } else {
+            Ty::Any
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Int => write!(f, "Int"),
+            Ty::Bool => write!(f, "Bool"),
+            Ty::String => write!(f, "String"),
+            Ty::Any => write!(f, "Any"),
+        }
+    }
+}
+
+//What a function takes from its caller's stack and what it leaves behind
+#[derive(Clone)]
+pub struct Signature {
+    pub inputs: Vec<Ty>,  //in the order they are popped, top of the stack first
+    pub outputs: Vec<Ty>, //bottom of the stack first
+    pub clears: bool,     //the caller's stack is cleared by the call
+    pub opaque: bool,     //the effect could not be worked out, e.g. through recursion
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.opaque {
+            return write!(f, "( ? )");
+        }
+
+        write!(f, "(")?;
+        if self.clears {
+            write!(f, " ;")?;
+        }
+        for t in self.inputs.iter().rev() {
+            write!(f, " {}", t)?;
+        }
+        write!(f, " --")?;
+        for t in &self.outputs {
+            write!(f, " {}", t)?;
+        }
+        write!(f, " )")
+    }
+}
+
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub signatures: Vec<(usize, String, Signature)>,
+}
+
+#[derive(Clone)]
+struct State {
+    stack: Vec<Ty>,
+    inputs: Vec<Ty>, //values taken from below the start of the function, in pop order
+    bottom: bool,    //nothing is below the stack, popping past it is an underflow
+    opaque: bool,    //the depth below the stack is no longer known
+    clears: bool,
+    vars: HashMap<String, Ty>,
+}
+
+impl State {
+    fn new(bottom: bool, vars: HashMap<String, Ty>) -> State {
+        State {
+            stack: Vec::<Ty>::new(),
+            inputs: Vec::<Ty>::new(),
+            bottom,
+            opaque: false,
+            clears: false,
+            vars,
+        }
+    }
+
+    //Depth relative to the start of the function, or absolute when the bottom is known
+    fn depth(&self) -> i64 {
+        self.stack.len() as i64 - self.inputs.len() as i64
+    }
+
+    //Whether the depths of two states that started out the same can be compared
+    fn comparable(&self, other: &State) -> bool {
+        !self.opaque && !other.opaque && self.bottom == other.bottom
+    }
+
+    fn pop(&mut self, want: Ty) -> Option<Ty> {
+        if let Some(t) = self.stack.pop() {
+            return Some(t);
+        }
+
+        if self.opaque {
+            Some(Ty::Any)
+        } else if self.bottom {
+            None
+        } else {
+            self.inputs.push(want);
+            Some(want)
+        }
+    }
+
+    //Take inputs until `n` have been taken, keeping them on the stack
+    fn take_inputs(&mut self, n: usize) {
+        while self.inputs.len() < n {
+            self.inputs.push(Ty::Any);
+            self.stack.insert(0, Ty::Any);
+        }
+    }
+}
+
+//Somewhere a '$' can go: a loop, or the function or top level below all of them
+struct Exit<'a> {
+    label: Option<&'a str>,
+    start: Option<State>, //the state at the head of a loop, where '^' goes back to
+    breaks: Vec<State>,
+}
+
+impl<'a> Exit<'a> {
+    fn new(label: Option<&'a str>, start: Option<State>) -> Exit<'a> {
+        Exit {
+            label,
+            start,
+            breaks: Vec::new(),
+        }
+    }
+
+    //The innermost exit with the label, or the innermost loop for '^'
+    fn find(exits: &[Exit], label: &Option<String>, continues: bool) -> Option<usize> {
+        exits.iter().rposition(|e| match label {
+            Some(l) => e.label == Some(l.as_str()),
+            None => !continues || e.start.is_some(),
+        })
+    }
+}
+
+struct Analyser<'a> {
+    funcs: HashMap<&'a str, &'a Block>,
+    assigned: HashSet<&'a str>,
+    signatures: HashMap<String, Option<Signature>>, //None while the function is being analysed
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub fn analyse(body: &[Node]) -> Analysis {
+    let mut analyser = Analyser {
+        funcs: HashMap::new(),
+        assigned: HashSet::new(),
+        signatures: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+    let mut defines = Vec::<(usize, &str)>::new();
+    analyser.collect(body, &mut defines);
+
+    let mut exits = vec![Exit::new(None, None)];
+    analyser.body(body, State::new(true, HashMap::new()), &mut exits);
+
+    //Functions which are never called still get a signature
+    let mut signatures = Vec::new();
+    for (pos, name) in defines {
+        if let Some(sig) = analyser.signature(name, &HashMap::new()) {
+            signatures.push((pos, name.to_string(), sig));
+        }
+    }
+
+    Analysis {
+        diagnostics: analyser.diagnostics,
+        signatures,
+    }
+}
+
+impl<'a> Analyser<'a> {
+    fn collect(&mut self, body: &'a [Node], defines: &mut Vec<(usize, &'a str)>) {
+        for node in body {
+            match &node.kind {
+                NodeKind::Operator(
+                    Operator::Assign(s)
+                    | Operator::Constant(s)
+                    | Operator::AssignOuter(s)
+                    | Operator::AssignGlobal(s),
+                )
+                | NodeKind::Count(s, _)
+                | NodeKind::Each(s, _) => {
+                    self.assigned.insert(s);
+                }
+                NodeKind::Define(s, b) => {
+                    //Later definitions replace earlier ones, as in register_functions
+                    self.funcs.insert(s, b);
+                    defines.retain(|(_, name)| name != s);
+                    defines.push((node.pos, s));
+                }
+                _ => (),
+            }
+
+            for block in node.kind.blocks() {
+                self.collect(&block.body, defines);
+            }
+        }
+    }
+
+    fn error(&mut self, pos: usize, msg: String) {
+        self.diagnostics.push(Diagnostic { pos, msg });
+    }
+
+    fn pop(&mut self, pos: usize, state: &mut State, want: Ty, err: &str) -> Ty {
+        match state.pop(want) {
+            Some(t) => {
+                if t != want && t != Ty::Any && want != Ty::Any {
+                    self.error(pos, format!("{} (found {})", err, t));
+                }
+                t
+            }
+            None => {
+                self.error(pos, "Cannot pop from empty stack!".to_string());
+                Ty::Any
+            }
+        }
+    }
+
+    //Returns the state after the body, or None if every path through it breaks
+    fn body(
+        &mut self,
+        body: &'a [Node],
+        mut state: State,
+        exits: &mut Vec<Exit<'a>>,
+    ) -> Option<State> {
+        let mut label = None;
+        for node in body {
+            match &node.kind {
+                NodeKind::Operand(o) => state.stack.push(Ty::of(o)),
+                //Nothing after a '!' runs, the handler gets the value instead
+                NodeKind::Operator(Operator::Throw) => {
+                    self.pop(node.pos, &mut state, Ty::Any, "");
+                    return None;
+                }
+                NodeKind::Operator(o) => self.operator(node.pos, o, &mut state),
+                NodeKind::Block(b) => state = self.body(&b.body, state, exits)?,
+                NodeKind::Loop(b) => {
+                    exits.push(Exit::new(label.take(), Some(state.clone())));
+                    let end = self.body(&b.body, state.clone(), exits);
+                    let exit = exits.pop()?;
+                    if let Some(end) = end {
+                        self.balanced(node.pos, &state, &end);
+                    }
+
+                    //Code after a loop with no '$' is never reached
+                    state = self.merge_all(
+                        node.pos,
+                        exit.breaks,
+                        "Breaks from loop leave the stack at different depths!",
+                    )?;
+                }
+                //Counted and for-each loops may run their body any number of times, including none
+                NodeKind::Count(name, b) | NodeKind::Each(name, b) => {
+                    let (want, err) = match &node.kind {
+                        NodeKind::Count(..) => (Ty::Int, "Counted loop requires an Int!"),
+                        _ => (Ty::String, "For-each loop requires a String!"),
+                    };
+                    self.pop(node.pos, &mut state, want, err);
+
+                    let mut start = state.clone();
+                    start.vars.insert(name.clone(), want);
+                    exits.push(Exit::new(label.take(), Some(start.clone())));
+                    let end = self.body(&b.body, start.clone(), exits);
+                    let exit = exits.pop()?;
+
+                    let mut states = vec![state];
+                    states.extend(exit.breaks);
+                    if let Some(end) = end {
+                        if self.balanced(node.pos, &start, &end) {
+                            states.push(end);
+                        }
+                    }
+                    state = self.merge_all(
+                        node.pos,
+                        states,
+                        "Breaks from loop leave the stack at different depths!",
+                    )?;
+                }
+                //The loop is left when the condition leaves false, or through a '$' in either block
+                NodeKind::While(c, b) => {
+                    exits.push(Exit::new(label.take(), Some(state.clone())));
+                    let mut cond = self.body(&c.body, state.clone(), exits);
+                    if let Some(cond) = &mut cond {
+                        self.pop(
+                            node.pos,
+                            cond,
+                            Ty::Bool,
+                            "While loop condition must leave a Bool!",
+                        );
+                        if let Some(end) = self.body(&b.body, cond.clone(), exits) {
+                            self.balanced(node.pos, &state, &end);
+                        }
+                    }
+                    let mut exit = exits.pop()?;
+
+                    exit.breaks.extend(cond);
+                    state = self.merge_all(
+                        node.pos,
+                        exit.breaks,
+                        "Breaks from loop leave the stack at different depths!",
+                    )?;
+                }
+                NodeKind::Cond(b, else_ifs, e) => {
+                    self.pop(
+                        node.pos,
+                        &mut state,
+                        Ty::Bool,
+                        "Conditional requires Bool at top of stack!",
+                    );
+
+                    let then = self.body(&b.body, state.clone(), exits);
+                    let other = self.else_branch(node.pos, else_ifs, e, state, exits);
+                    state = self.merge(
+                        node.pos,
+                        then,
+                        other,
+                        "Branches of conditional leave the stack at different depths!",
+                    )?;
+                }
+                //The handler starts from the stack as it was at the '!?', with the value and message
+                NodeKind::Try(b, h) => {
+                    let body = self.body(&b.body, state.clone(), exits);
+                    let mut caught = state;
+                    caught.stack.push(Ty::Any);
+                    caught.stack.push(Ty::String);
+                    let handler = self.body(&h.body, caught, exits);
+                    state = self.merge(
+                        node.pos,
+                        body,
+                        handler,
+                        "Try and its handler leave the stack at different depths!",
+                    )?;
+                }
+                NodeKind::Match(cases) => {
+                    self.pop(node.pos, &mut state, Ty::Any, "");
+
+                    let mut states = Vec::<Option<State>>::new();
+                    for case in cases {
+                        states.push(self.body(&case.block.body, state.clone(), exits));
+                    }
+                    if !cases.iter().any(|c| c.value.is_none()) {
+                        states.push(Some(state));
+                    }
+
+                    let mut merged = None;
+                    for other in states {
+                        merged = self.merge(
+                            node.pos,
+                            merged,
+                            other,
+                            "Cases of match leave the stack at different depths!",
+                        );
+                    }
+                    state = merged?;
+                }
+                NodeKind::Define(..) => (),
+                NodeKind::Label(l) => {
+                    label = Some(l.as_str());
+                    continue;
+                }
+                //A '$' or '^' with no loop to go to is reported by check
+                NodeKind::Break(l) => {
+                    if let Some(i) = Exit::find(exits, l, false) {
+                        exits[i].breaks.push(state);
+                    }
+                    return None;
+                }
+                //The function's exit is always the first
+                NodeKind::Return => {
+                    exits[0].breaks.push(state);
+                    return None;
+                }
+                //Going on to the next iteration is the same as reaching the end of the body
+                NodeKind::Continue(l) => {
+                    if let Some(i) = Exit::find(exits, l, true) {
+                        if let Some(start) = &exits[i].start {
+                            self.balanced(node.pos, start, &state);
+                        }
+                    }
+                    return None;
+                }
+            }
+            label = None;
+        }
+
+        Some(state)
+    }
+
+    //The state after whichever of the else-ifs and else runs, when the first branch did not
+    fn else_branch(
+        &mut self,
+        pos: usize,
+        else_ifs: &'a [(Block, Block)],
+        e: &'a Option<Block>,
+        state: State,
+        exits: &mut Vec<Exit<'a>>,
+    ) -> Option<State> {
+        let ((c, b), rest) = match else_ifs.split_first() {
+            Some(first) => first,
+            None => {
+                return match e {
+                    Some(e) => self.body(&e.body, state, exits),
+                    None => Some(state),
+                }
+            }
+        };
+
+        let mut state = self.body(&c.body, state, exits)?;
+        self.pop(
+            pos,
+            &mut state,
+            Ty::Bool,
+            "Conditional requires Bool at top of stack!",
+        );
+        let then = self.body(&b.body, state.clone(), exits);
+        let other = self.else_branch(pos, rest, e, state, exits);
+        self.merge(
+            pos,
+            then,
+            other,
+            "Branches of conditional leave the stack at different depths!",
+        )
+    }
+
+    //A loop body must leave the stack as deep as it found it
+    fn balanced(&mut self, pos: usize, start: &State, end: &State) -> bool {
+        let growth = end.depth() - start.depth();
+        if end.comparable(start) && growth > 0 {
+            self.error(
+                pos,
+                format!("Loop body grows the stack by {} each iteration!", growth),
+            );
+        } else if end.comparable(start) && growth < 0 {
+            self.error(
+                pos,
+                format!("Loop body shrinks the stack by {} each iteration!", -growth),
+            );
+        }
+
+        growth == 0 || !end.comparable(start)
+    }
+
+    fn merge(
+        &mut self,
+        pos: usize,
+        a: Option<State>,
+        b: Option<State>,
+        err: &str,
+    ) -> Option<State> {
+        let (mut a, mut b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            (a, b) => return a.or(b),
+        };
+
+        if !a.comparable(&b) {
+            a.opaque = true;
+            return Some(a);
+        }
+        if a.depth() != b.depth() {
+            self.error(pos, format!("{} ({} vs {})", err, a.depth(), b.depth()));
+            return Some(a);
+        }
+
+        //Make both sides take the same inputs, so the stacks line up
+        let n = a.inputs.len().max(b.inputs.len());
+        a.take_inputs(n);
+        b.take_inputs(n);
+
+        for (t, u) in a.stack.iter_mut().zip(b.stack) {
+            *t = t.join(u);
+        }
+        for (t, u) in a.inputs.iter_mut().zip(b.inputs) {
+            *t = t.join(u);
+        }
+        for (name, t) in a.vars.iter_mut() {
+            *t = match b.vars.get(name) {
+                Some(u) => t.join(*u),
+                None => Ty::Any,
+            };
+        }
+        for (name, _) in b.vars {
+            a.vars.entry(name).or_insert(Ty::Any);
+        }
+        a.clears |= b.clears;
+
+        Some(a)
+    }
+
+    fn merge_all(&mut self, pos: usize, states: Vec<State>, err: &str) -> Option<State> {
+        let mut merged = None;
+        for state in states {
+            merged = self.merge(pos, merged, Some(state), err);
+        }
+
+        merged
+    }
+
+    //Works out the signature of a function the first time it is called, with the variables
+    //visible at that call. Returns None when the function is already being analysed
+    fn signature(&mut self, name: &str, vars: &HashMap<String, Ty>) -> Option<Signature> {
+        if let Some(sig) = self.signatures.get(name) {
+            return sig.clone();
+        }
+        let block = self.funcs[name];
+        self.signatures.insert(name.to_string(), None);
+
+        let mut exits = vec![Exit::new(None, None)];
+        let end = self.body(&block.body, State::new(false, vars.clone()), &mut exits);
+        let mut returns = exits.pop().map(|e| e.breaks).unwrap_or_default();
+        returns.extend(end);
+
+        let sig = match self.merge_all(
+            block.open,
+            returns,
+            "Returns from function leave the stack at different depths!",
+        ) {
+            Some(s) => Signature {
+                inputs: s.inputs,
+                outputs: s.stack,
+                clears: s.clears,
+                opaque: s.opaque,
+            },
+            //Never returns
+            None => Signature {
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                clears: false,
+                opaque: false,
+            },
+        };
+
+        self.signatures.insert(name.to_string(), Some(sig.clone()));
+        Some(sig)
+    }
+
+    fn call(&mut self, pos: usize, name: &str, state: &mut State) {
+        let sig = match self.signature(name, &state.vars) {
+            Some(s) => s,
+            None => {
+                state.opaque = true;
+                return;
+            }
+        };
+
+        for t in &sig.inputs {
+            self.pop(
+                pos,
+                state,
+                *t,
+                &format!("Wrong argument type for '{}'", name),
+            );
+        }
+        if sig.clears {
+            state.stack.clear();
+            state.bottom = true;
+            state.clears = true;
+        }
+        state.stack.extend(sig.outputs.iter());
+        if sig.opaque {
+            state.opaque = true;
+        }
+    }
+
+    fn operator(&mut self, pos: usize, op: &Operator, state: &mut State) {
+        match op {
+            Operator::Add => {
+                let a = self.pop(pos, state, Ty::Any, "");
+                let b = self.pop(pos, state, Ty::Any, "");
+                let t = match (a, b) {
+                    (Ty::Any, _) | (_, Ty::Any) => Ty::Any,
+                    (Ty::Int, Ty::Int) | (Ty::String, Ty::Int) => Ty::Int,
+                    (Ty::Int, Ty::String) | (Ty::String, Ty::String) => Ty::String,
+                    (Ty::String, Ty::Bool) | (Ty::Bool, Ty::String) => Ty::String,
+                    (Ty::Bool, _) => {
+                        self.error(pos, "Can only append Bools to Strings!".to_string());
+                        Ty::Any
+                    }
+                    _ => {
+                        self.error(pos, "Add only implemented for Int and String".to_string());
+                        Ty::Any
+                    }
+                };
+                state.stack.push(t);
+            }
+            Operator::Sub | Operator::Mul | Operator::Div => {
+                let err = match op {
+                    Operator::Sub => "Sub only implemented for int",
+                    _ => "Mul only implemented for int",
+                };
+                self.pop(pos, state, Ty::Int, err);
+                self.pop(pos, state, Ty::Int, err);
+                state.stack.push(Ty::Int);
+            }
+            Operator::Equal | Operator::NotEqual => {
+                let a = self.pop(pos, state, Ty::Any, "");
+                let err = match a {
+                    Ty::Int => "Can only compare Int with Int!",
+                    Ty::Bool => "Can only compare Bool with Bool!",
+                    _ => "Can only compare String with String!",
+                };
+                self.pop(pos, state, a, err);
+                state.stack.push(Ty::Bool);
+            }
+            Operator::LessThan | Operator::GreaterThan => {
+                self.pop(pos, state, Ty::Int, "Can only compare Ints!");
+                self.pop(pos, state, Ty::Int, "Can only compare Ints!");
+                state.stack.push(Ty::Bool);
+            }
+            Operator::Cond => {
+                self.pop(
+                    pos,
+                    state,
+                    Ty::Bool,
+                    "Conditional requires Bool at top of stack!",
+                );
+            }
+            Operator::Pop => {
+                self.pop(pos, state, Ty::Any, "");
+            }
+            Operator::Clear => {
+                state.stack.clear();
+                state.bottom = true;
+                state.clears = true;
+            }
+            Operator::Assign(s)
+            | Operator::Constant(s)
+            | Operator::AssignOuter(s)
+            | Operator::AssignGlobal(s) => {
+                let t = self.pop(pos, state, Ty::Any, "");
+                state.vars.insert(s.clone(), t);
+            }
+            Operator::Access(s) => {
+                if let Some(t) = state.vars.get(s) {
+                    state.stack.push(*t);
+                } else if !self.assigned.contains(s.as_str()) && self.funcs.contains_key(s.as_str())
+                {
+                    self.call(pos, s, state);
+                } else {
+                    state.stack.push(Ty::Any);
+                }
+            }
+            Operator::Index => {
+                self.pop(pos, state, Ty::Int, "Index must be an Int!");
+                self.pop(pos, state, Ty::String, "Can only index Strings!");
+                state.stack.push(Ty::String);
+            }
+            Operator::Input => state.stack.push(Ty::String),
+            Operator::Throw => {
+                self.pop(pos, state, Ty::Any, "");
+            }
+            Operator::Assert => {
+                self.pop(pos, state, Ty::Bool, "Assert requires a Bool!");
+            }
+            Operator::AssertEq => {
+                self.pop(pos, state, Ty::Any, "");
+                self.pop(pos, state, Ty::Any, "");
+            }
+            Operator::Print => (),
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//JIT - compiles integer-only loops to x86-64 machine code the first time they are entered.
+//Anything other than Int arithmetic, comparisons, conditionals, breaks and Int variables
+//leaves the loop to the interpreter, as do variables that do not hold an Int when the loop starts
+use crate::parse::{self, Node, NodeKind, Program};
+use crate::{Lexer, Operand, Operator, VarStore};
+use std::collections::HashMap;
+
+//Messages for the error codes returned by compiled code, the same as the interpreter's errors
+const ERRORS: [&str; 5] = [
+    "attempt to add with overflow",
+    "attempt to subtract with overflow",
+    "attempt to multiply with overflow",
+    "attempt to divide by zero",
+    "attempt to divide with overflow",
+];
+
+pub struct Jit {
+    program: Program,
+    loops: HashMap<usize, Option<Native>>, //by position of the '~', None if it cannot be compiled
+}
+
+struct Native {
+    code: exec::Code,
+    vars: Vec<(usize, bool)>, //id of the name in each slot, and whether the loop assigns to it
+    close: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+}
+
+//Types on the native stack, None once control can no longer reach this point
+type State = Option<Vec<Ty>>;
+
+struct Compiler<'a> {
+    code: Vec<u8>,
+    vars: Vec<(&'a str, bool)>,
+    loops: Vec<(Vec<Ty>, Vec<usize>)>, //stack at entry and breaks to patch, for each open loop
+    errors: Vec<(usize, i32)>,         //jumps to patch to an error exit, with its code
+}
+
+pub fn supported() -> bool {
+    cfg!(all(target_os = "linux", target_arch = "x86_64"))
+}
+
+impl Jit {
+    pub fn new(lex: &mut Lexer) -> Jit {
+        let mut program = parse::parse(lex);
+        //The parser recovers from errors in ways the interpreter does not, skipping blocks or
+        //keeping ones the interpreter never runs, so such programs are left to the interpreter
+        if !program.diagnostics.is_empty() {
+            program.body.clear();
+        }
+
+        Jit {
+            program,
+            loops: HashMap::new(),
+        }
+    }
+
+    //Run the loop whose '~' is at pos natively. Returns the position of its closing brace,
+    //or None if the interpreter has to run it instead
+    pub fn run(&mut self, pos: usize, var_store: &mut VarStore) -> Result<Option<usize>, String> {
+        let program = &self.program;
+        let native =
+            match self.loops.entry(pos).or_insert_with(|| {
+                find(&program.body, pos).and_then(|n| compile(n, &var_store.ids))
+            }) {
+                Some(native) => native,
+                None => return Ok(None),
+            };
+
+        let mut slots = Vec::<i32>::new();
+        for &(id, assigned) in &native.vars {
+            //Assigning writes to the innermost scope, so the variable must already be there.
+            //Constants are left to the interpreter to report
+            let value = match assigned {
+                true if var_store.constant(id).is_some() => None,
+                true => var_store.get(var_store.vars.len() - 1, id).cloned(),
+                false => var_store.get_var(id),
+            };
+            match value {
+                Some(Operand::Int(i)) => slots.push(i),
+                _ => return Ok(None),
+            }
+        }
+
+        //Assignments made before an error are kept, as they are by the interpreter, in case
+        //a '!?' catches it
+        let result = native.code.call(slots.as_mut_ptr());
+        for (&(id, assigned), i) in native.vars.iter().zip(slots) {
+            if assigned {
+                var_store.set_var(id, Operand::Int(i))?;
+            }
+        }
+
+        match result {
+            0 => Ok(Some(native.close)),
+            e => Err(ERRORS[e as usize - 1].to_string()),
+        }
+    }
+}
+
+fn find(body: &[Node], pos: usize) -> Option<&Node> {
+    for node in body {
+        if node.pos == pos {
+            return Some(node);
+        }
+
+        for block in node.kind.blocks() {
+            if let Some(n) = find(&block.body, pos) {
+                return Some(n);
+            }
+        }
+    }
+
+    None
+}
+
+//Names are looked up in ids, the interpreter's, once here rather than each time the loop runs
+fn compile(node: &Node, ids: &HashMap<String, usize>) -> Option<Native> {
+    let block = match &node.kind {
+        NodeKind::Loop(b) => b,
+        _ => return None,
+    };
+    let close = block.close?;
+
+    let mut c = Compiler {
+        code: Vec::new(),
+        vars: Vec::new(),
+        loops: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    //push rbx; push rbp; mov rbp, rsp; mov rbx, rdi
+    c.emit(&[0x53, 0x55, 0x48, 0x89, 0xE5, 0x48, 0x89, 0xFB]);
+    c.looped(&block.body, Some(Vec::new()))?;
+    //xor eax, eax
+    c.emit(&[0x31, 0xC0]);
+    let epilogue = c.code.len();
+    //mov rsp, rbp; pop rbp; pop rbx; ret
+    c.emit(&[0x48, 0x89, 0xEC, 0x5D, 0x5B, 0xC3]);
+
+    for (at, e) in std::mem::take(&mut c.errors) {
+        c.patch(at, c.code.len());
+        //mov eax, e; jmp epilogue
+        c.emit(&[0xB8]);
+        c.emit(&e.to_le_bytes());
+        let at = c.jump(&[0xE9]);
+        c.patch(at, epilogue);
+    }
+
+    Some(Native {
+        code: exec::Code::new(&c.code)?,
+        vars: c
+            .vars
+            .iter()
+            .map(|(n, a)| Some((*ids.get(*n)?, *a)))
+            .collect::<Option<Vec<(usize, bool)>>>()?,
+        close,
+    })
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    //Emit a jump with a rel32 to be patched later, returning where the rel32 is
+    fn jump(&mut self, opcode: &[u8]) -> usize {
+        self.emit(opcode);
+        self.emit(&[0; 4]);
+        self.code.len() - 4
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        let rel = (target as i64 - (at as i64 + 4)) as i32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    fn overflow(&mut self, e: i32) {
+        //jo
+        let at = self.jump(&[0x0F, 0x80]);
+        self.errors.push((at, e));
+    }
+
+    fn slot(&mut self, name: &'a str, assign: bool) -> [u8; 4] {
+        let i = match self.vars.iter().position(|(n, _)| *n == name) {
+            Some(i) => i,
+            None => {
+                self.vars.push((name, false));
+                self.vars.len() - 1
+            }
+        };
+        self.vars[i].1 |= assign;
+        ((i * 4) as u32).to_le_bytes()
+    }
+
+    //Pop the top two types, which must match and be one of tys, leaving the operands in ecx and eax
+    fn binary(&mut self, stack: &mut Vec<Ty>, tys: &[Ty]) -> Option<()> {
+        let a = stack.pop()?;
+        let b = stack.pop()?;
+        if a != b || !tys.contains(&a) {
+            return None;
+        }
+
+        //pop rcx; pop rax
+        self.emit(&[0x59, 0x58]);
+        Some(())
+    }
+
+    fn looped(&mut self, body: &'a [Node], state: State) -> Option<State> {
+        let entry = state?;
+        let head = self.code.len();
+        self.loops.push((entry.clone(), Vec::new()));
+
+        //Every iteration must leave the stack as it found it
+        if let Some(end) = self.body(body, Some(entry.clone()))? {
+            if end != entry {
+                return None;
+            }
+        }
+        let at = self.jump(&[0xE9]);
+        self.patch(at, head);
+
+        let (_, breaks) = self.loops.pop()?;
+        let exit = self.code.len();
+        for at in &breaks {
+            self.patch(*at, exit);
+        }
+
+        Some(match breaks.is_empty() {
+            true => None,
+            false => Some(entry),
+        })
+    }
+
+    fn body(&mut self, body: &'a [Node], mut state: State) -> Option<State> {
+        for node in body {
+            let stack = match &mut state {
+                Some(s) => s,
+                //Nothing after a break is ever run
+                None => break,
+            };
+
+            match &node.kind {
+                NodeKind::Operand(Operand::Int(i)) => {
+                    //push imm32
+                    self.emit(&[0x68]);
+                    self.emit(&i.to_le_bytes());
+                    stack.push(Ty::Int);
+                }
+                NodeKind::Operand(Operand::Bool(b)) => {
+                    self.emit(&[0x68]);
+                    self.emit(&(*b as i32).to_le_bytes());
+                    stack.push(Ty::Bool);
+                }
+                NodeKind::Operand(Operand::String(_)) => return None,
+                NodeKind::Operator(o) => self.operator(o, stack)?,
+                NodeKind::Block(b) => state = self.body(&b.body, state)?,
+                NodeKind::Loop(b) => state = self.looped(&b.body, state)?,
+                //Their loop variables live outside the slots, so they are left to the interpreter
+                NodeKind::Count(..) | NodeKind::Each(..) | NodeKind::While(..) => return None,
+                //Else-ifs and matches are left to the interpreter
+                NodeKind::Cond(_, else_ifs, _) if !else_ifs.is_empty() => return None,
+                NodeKind::Match(_) | NodeKind::Try(..) => return None,
+                NodeKind::Cond(b, _, e) => {
+                    if stack.pop()? != Ty::Bool {
+                        return None;
+                    }
+                    //pop rax; test eax, eax; jz
+                    self.emit(&[0x58, 0x85, 0xC0]);
+                    let skip = self.jump(&[0x0F, 0x84]);
+
+                    let after = state.clone();
+                    let then = self.body(&b.body, after.clone())?;
+                    let otherwise = match e {
+                        Some(e) => {
+                            let end = self.jump(&[0xE9]);
+                            self.patch(skip, self.code.len());
+                            let otherwise = self.body(&e.body, after)?;
+                            self.patch(end, self.code.len());
+                            otherwise
+                        }
+                        None => {
+                            self.patch(skip, self.code.len());
+                            after
+                        }
+                    };
+
+                    state = match (then, otherwise) {
+                        (Some(a), Some(b)) if a != b => return None,
+                        (Some(a), _) => Some(a),
+                        (None, b) => b,
+                    };
+                }
+                //Skipped over by the interpreter too
+                NodeKind::Define(..) => (),
+                NodeKind::Break(None) => {
+                    let (entry, _) = self.loops.last()?;
+                    if stack != entry {
+                        return None;
+                    }
+                    let at = self.jump(&[0xE9]);
+                    self.loops.last_mut()?.1.push(at);
+                    state = None;
+                }
+                NodeKind::Label(_)
+                | NodeKind::Break(Some(_))
+                | NodeKind::Continue(_)
+                | NodeKind::Return => return None,
+            }
+        }
+
+        Some(state)
+    }
+
+    fn operator(&mut self, o: &'a Operator, stack: &mut Vec<Ty>) -> Option<()> {
+        match o {
+            Operator::Add => {
+                self.binary(stack, &[Ty::Int])?;
+                //add eax, ecx
+                self.emit(&[0x01, 0xC8]);
+                self.overflow(1);
+            }
+            Operator::Sub => {
+                self.binary(stack, &[Ty::Int])?;
+                //sub eax, ecx
+                self.emit(&[0x29, 0xC8]);
+                self.overflow(2);
+            }
+            Operator::Mul => {
+                self.binary(stack, &[Ty::Int])?;
+                //imul eax, ecx
+                self.emit(&[0x0F, 0xAF, 0xC1]);
+                self.overflow(3);
+            }
+            Operator::Div => {
+                self.binary(stack, &[Ty::Int])?;
+                //test ecx, ecx; jz
+                self.emit(&[0x85, 0xC9]);
+                let at = self.jump(&[0x0F, 0x84]);
+                self.errors.push((at, 4));
+                //cmp ecx, -1; jne ok; cmp eax, i32::MIN; je
+                self.emit(&[0x83, 0xF9, 0xFF]);
+                let ok = self.jump(&[0x0F, 0x85]);
+                self.emit(&[0x3D, 0x00, 0x00, 0x00, 0x80]);
+                let at = self.jump(&[0x0F, 0x84]);
+                self.errors.push((at, 5));
+                self.patch(ok, self.code.len());
+                //cdq; idiv ecx
+                self.emit(&[0x99, 0xF7, 0xF9]);
+            }
+            Operator::Equal | Operator::NotEqual | Operator::LessThan | Operator::GreaterThan => {
+                let (tys, setcc): (&[Ty], u8) = match o {
+                    Operator::Equal => (&[Ty::Int, Ty::Bool], 0x94),
+                    Operator::NotEqual => (&[Ty::Int, Ty::Bool], 0x95),
+                    Operator::LessThan => (&[Ty::Int], 0x9C),
+                    _ => (&[Ty::Int], 0x9F),
+                };
+                self.binary(stack, tys)?;
+                //cmp eax, ecx; setcc al; movzx eax, al
+                self.emit(&[0x39, 0xC8, 0x0F, setcc, 0xC0, 0x0F, 0xB6, 0xC0]);
+                stack.push(Ty::Bool);
+                //push rax
+                self.emit(&[0x50]);
+                return Some(());
+            }
+            Operator::Pop => {
+                stack.pop()?;
+                //pop rax
+                self.emit(&[0x58]);
+                return Some(());
+            }
+            Operator::Access(name) => {
+                let slot = self.slot(name, false);
+                //mov eax, [rbx + slot]
+                self.emit(&[0x8B, 0x83]);
+                self.emit(&slot);
+                stack.push(Ty::Int);
+                self.emit(&[0x50]);
+                return Some(());
+            }
+            Operator::Assign(name) => {
+                if stack.pop()? != Ty::Int {
+                    return None;
+                }
+                let slot = self.slot(name, true);
+                //pop rax; mov [rbx + slot], eax
+                self.emit(&[0x58, 0x89, 0x83]);
+                self.emit(&slot);
+                return Some(());
+            }
+            //Print, Clear, Index, Input and a '?' without a block need the interpreter
+            _ => return None,
+        }
+
+        stack.push(Ty::Int);
+        self.emit(&[0x50]);
+        Some(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod exec {
+    use std::ffi::c_void;
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            off: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    //Executable copy of some machine code. Never writable and executable at the same time
+    pub struct Code {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    impl Code {
+        pub fn new(bytes: &[u8]) -> Option<Code> {
+            let len = bytes.len();
+            unsafe {
+                let ptr = mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if ptr as isize == -1 {
+                    return None;
+                }
+
+                let code = Code { ptr, len };
+                std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as *mut u8, len);
+                if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                    return None;
+                }
+                Some(code)
+            }
+        }
+
+        //Run the code with the variable slots in rdi, returning 0 or an error code
+        pub fn call(&self, vars: *mut i32) -> i32 {
+            unsafe {
+                let f: extern "C" fn(*mut i32) -> i32 = std::mem::transmute(self.ptr);
+                f(vars)
+            }
+        }
+    }
+
+    impl Drop for Code {
+        fn drop(&mut self) {
+            unsafe {
+                munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod exec {
+    //Machine code cannot be run here, so every loop is left to the interpreter
+    pub struct Code;
+
+    impl Code {
+        pub fn new(_bytes: &[u8]) -> Option<Code> {
+            None
+        }
+
+        pub fn call(&self, _vars: *mut i32) -> i32 {
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//Minimal JSON values, for talking to editor tooling
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    //Look up a field of an object, giving Null when it is missing
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(o) => match o.iter().find(|(k, _)| k == key) {
+                Some((_, v)) => v,
+                None => &Json::Null,
+            },
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Json> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            current: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.current < parser.chars.len() {
+            return None;
+        }
+
+        Some(value)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    current: usize,
+}
+
+impl Parser {
+    fn whitespace(&mut self) {
+        while self.current < self.chars.len() && self.chars[self.current].is_whitespace() {
+            self.current += 1;
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = *self.chars.get(self.current)?;
+        self.current += 1;
+        Some(c)
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Option<Json> {
+        for c in word.chars() {
+            if self.next()? != c {
+                return None;
+            }
+        }
+
+        Some(value)
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.whitespace();
+        match *self.chars.get(self.current)? {
+            'n' => self.keyword("null", Json::Null),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            '"' => Some(Json::String(self.string()?)),
+            '[' => {
+                self.current += 1;
+                let mut list = Vec::<Json>::new();
+                self.whitespace();
+                if self.chars.get(self.current) == Some(&']') {
+                    self.current += 1;
+                    return Some(Json::Array(list));
+                }
+
+                loop {
+                    list.push(self.value()?);
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Some(Json::Array(list)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.current += 1;
+                let mut fields = Vec::<(String, Json)>::new();
+                self.whitespace();
+                if self.chars.get(self.current) == Some(&'}') {
+                    self.current += 1;
+                    return Some(Json::Object(fields));
+                }
+
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.whitespace();
+                    if self.next()? != ':' {
+                        return None;
+                    }
+                    fields.push((key, self.value()?));
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => self.number(),
+        }
+    }
+
+    //Fractions and exponents are read, but the value is truncated to an integer
+    fn number(&mut self) -> Option<Json> {
+        let start = self.current;
+        while self.current < self.chars.len()
+            && matches!(
+                self.chars[self.current],
+                '-' | '+' | '.' | 'e' | 'E' | '0'..='9'
+            )
+        {
+            self.current += 1;
+        }
+
+        let text: String = self.chars[start..self.current].iter().collect();
+        match text.parse::<i64>() {
+            Ok(i) => Some(Json::Int(i)),
+            Err(_) => text.parse::<f64>().ok().map(|f| Json::Int(f as i64)),
+        }
+    }
+
+    fn hex(&mut self) -> Option<u32> {
+        let mut n = 0;
+        for _ in 0..4 {
+            n = n * 16 + self.next()?.to_digit(16)?;
+        }
+
+        Some(n)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.next()? != '"' {
+            return None;
+        }
+
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(s),
+                '\\' => match self.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let mut n = self.hex()?;
+                        //Characters outside the BMP are written as a surrogate pair
+                        if (0xD800..0xDC00).contains(&n) {
+                            if self.next()? != '\\' || self.next()? != 'u' {
+                                return None;
+                            }
+                            let low = self.hex()?;
+                            n = 0x10000 + ((n - 0xD800) << 10) + (low.checked_sub(0xDC00)?);
+                        }
+                        s.push(char::from_u32(n)?);
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(i: usize) -> Json {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(i: i32) -> Json {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::String(s) => write_str(f, s),
+            Json::Array(a) => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(o) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in o.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn escapes_strings() {
+        let s = Json::from("quote \" backslash \\ newline \n tab \t return \r bell \u{7} é 😀");
+        assert_eq!(
+            s.to_string(),
+            r#""quote \" backslash \\ newline \n tab \t return \r bell \u0007 é 😀""#
+        );
+        let o = Json::object(vec![(
+            "a\"b",
+            Json::Array(vec![Json::Null, Json::from(true)]),
+        )]);
+        assert_eq!(o.to_string(), r#"{"a\"b":[null,true]}"#);
+    }
+
+    #[test]
+    fn parses_values() {
+        let json = r#" { "id" : -12, "ok" : true, "no": false, "none" : null,
+            "list" : [ 1 , [ ] , { } ], "n": 2.5e1 } "#;
+        let value = Json::parse(json).unwrap();
+        assert_eq!(value.get("id").as_i64(), Some(-12));
+        assert_eq!(value.get("ok"), &Json::Bool(true));
+        assert_eq!(value.get("no"), &Json::Bool(false));
+        assert_eq!(value.get("none"), &Json::Null);
+        assert_eq!(value.get("missing"), &Json::Null);
+        assert_eq!(
+            value.get("list"),
+            &Json::Array(vec![
+                Json::Int(1),
+                Json::Array(vec![]),
+                Json::Object(vec![])
+            ])
+        );
+        assert_eq!(value.get("n").as_i64(), Some(25));
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let value = Json::parse(r#""a\"b\\c\/d\n\t\r\b\fé😀""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\n\t\r\u{8}\u{c}é😀"));
+        let value = Json::parse(r#""\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(value.as_str(), Some("é😀"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = Json::object(vec![
+            ("text", Json::from("line\n\"quoted\" \u{1} 😀")),
+            ("values", Json::Array(vec![Json::Int(-1), Json::Null])),
+        ]);
+        assert_eq!(Json::parse(&value.to_string()), Some(value));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for json in [
+            "",
+            "{",
+            "[1,",
+            "[1 2]",
+            r#"{"a" 1}"#,
+            r#"{"a":1,}"#,
+            r#""unterminated"#,
+            r#""\u12""#,
+            r#""\ud83d""#,
+            r#""\ud83dA""#,
+            "nul",
+            "true false",
+            "-",
+        ] {
+            assert_eq!(Json::parse(json), None, "{:?}", json);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//Language server - speaks the Language Server Protocol over stdin and stdout
+use crate::check;
+use crate::infer;
+use crate::json::Json;
+use crate::parse::{self, Node, NodeKind, Program};
+use crate::{Lexer, Op, Operator};
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+const SYMBOL_CONSTANT: i64 = 14;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_CONSTANT: i64 = 21;
+
+//A parsed copy of an open file
+struct Document {
+    lex: Lexer,
+    program: Program,
+}
+
+impl Document {
+    fn new(text: &str) -> Document {
+        let mut lex = Lexer::new("\n".to_string() + text);
+        let program = parse::parse(&mut lex);
+        Document { lex, program }
+    }
+
+    //LSP positions are 0-based lines, with the column counted in UTF-16 code units
+    fn position(&self, pos: usize) -> Json {
+        let (line, col) = self.lex.location(pos);
+        let line_start = pos + 1 - col;
+        let character: usize = self.lex.chars[line_start..pos]
+            .iter()
+            .map(|c| c.len_utf16())
+            .sum();
+
+        Json::object(vec![
+            ("line", Json::from(line - 1)),
+            ("character", Json::from(character)),
+        ])
+    }
+
+    fn offset(&self, position: &Json) -> usize {
+        let line = position.get("line").as_i64().unwrap_or(0) as usize;
+        let mut character = position.get("character").as_i64().unwrap_or(0) as usize;
+
+        //Skip the newline added at the start, then `line` more lines
+        let mut pos = 1;
+        for _ in 0..line {
+            while pos < self.lex.chars.len() && self.lex.chars[pos] != '\n' {
+                pos += 1;
+            }
+            pos += 1;
+        }
+        while pos < self.lex.chars.len() && self.lex.chars[pos] != '\n' && character > 0 {
+            character = character.saturating_sub(self.lex.chars[pos].len_utf16());
+            pos += 1;
+        }
+
+        pos
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        Json::object(vec![
+            ("start", self.position(start)),
+            ("end", self.position(end)),
+        ])
+    }
+
+    //The token under the cursor
+    fn token_at(&mut self, pos: usize) -> Option<parse::Token> {
+        parse::tokens(&mut self.lex)
+            .into_iter()
+            .find(|t| t.start <= pos && pos <= t.end + 1)
+    }
+
+    //Every function definition, as (name, position of '#', position of '}')
+    fn functions(&self) -> Vec<(String, usize, Option<usize>)> {
+        let mut found = Vec::new();
+        collect_functions(&self.program.body, &mut found);
+        found
+    }
+}
+
+fn collect_functions(body: &[Node], found: &mut Vec<(String, usize, Option<usize>)>) {
+    for node in body {
+        if let NodeKind::Define(name, block) = &node.kind {
+            //Later definitions replace earlier ones, as in register_functions
+            found.retain(|(n, _, _)| n != name);
+            found.push((name.clone(), node.pos, block.close));
+        }
+
+        for block in node.kind.blocks() {
+            collect_functions(&block.body, found);
+        }
+    }
+}
+
+//Each name with where it is first bound, and whether that binding is a constant
+fn collect_variables(body: &[Node], found: &mut Vec<(String, usize, bool)>) {
+    for node in body {
+        if let NodeKind::Operator(
+            Operator::Assign(name)
+            | Operator::Constant(name)
+            | Operator::AssignOuter(name)
+            | Operator::AssignGlobal(name),
+        )
+        | NodeKind::Count(name, _)
+        | NodeKind::Each(name, _) = &node.kind
+        {
+            if !found.iter().any(|(n, _, _)| n == name) {
+                let constant = matches!(node.kind, NodeKind::Operator(Operator::Constant(_)));
+                found.push((name.clone(), node.pos, constant));
+            }
+        }
+
+        for block in node.kind.blocks() {
+            collect_variables(&block.body, found);
+        }
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length:") {
+            length = v.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0u8; length?];
+    input.read_exact(&mut body).ok()?;
+    Json::parse(&String::from_utf8_lossy(&body))
+}
+
+//Fails once the client has stopped reading, which ends the server
+fn send(message: Json) -> io::Result<()> {
+    let body = message.to_string();
+    let mut out = io::stdout().lock();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn respond(id: &Json, result: Json) -> io::Result<()> {
+    send(Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("id", id.clone()),
+        ("result", result),
+    ]))
+}
+
+fn notify(method: &str, params: Json) -> io::Result<()> {
+    send(Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("method", Json::from(method)),
+        ("params", params),
+    ]))
+}
+
+fn publish_diagnostics(uri: &str, text: &str) -> io::Result<()> {
+    let mut doc = Document::new(text);
+    let analysis = check::check(&mut doc.lex);
+    let tokens = parse::tokens(&mut doc.lex);
+    let diagnostics = analysis
+        .diagnostics
+        .iter()
+        .map(|d| {
+            let end = match tokens.iter().find(|t| t.start == d.pos) {
+                Some(t) => t.end + 1,
+                None => d.pos + 1,
+            };
+            Json::object(vec![
+                ("range", doc.range(d.pos, end.min(doc.lex.chars.len()))),
+                ("severity", Json::Int(1)),
+                ("source", Json::from("aqua")),
+                ("message", Json::from(d.msg.as_str())),
+            ])
+        })
+        .collect::<Vec<Json>>();
+
+    notify(
+        "textDocument/publishDiagnostics",
+        Json::object(vec![
+            ("uri", Json::from(uri)),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]),
+    )
+}
+
+fn definition(doc: &mut Document, uri: &str, params: &Json) -> Json {
+    let pos = doc.offset(params.get("position"));
+    let name = match doc.token_at(pos) {
+        Some(parse::Token {
+            op: Op::Operator(Operator::Access(name)),
+            ..
+        }) => name,
+        _ => return Json::Null,
+    };
+
+    match doc.functions().into_iter().find(|(n, _, _)| *n == name) {
+        Some((_, start, _)) => Json::object(vec![
+            ("uri", Json::from(uri)),
+            ("range", doc.range(start, start + name.chars().count() + 1)),
+        ]),
+        None => Json::Null,
+    }
+}
+
+fn hover(doc: &mut Document, params: &Json) -> Json {
+    let pos = doc.offset(params.get("position"));
+    let token = match doc.token_at(pos) {
+        Some(t) => t,
+        None => return Json::Null,
+    };
+    let name = match &token.op {
+        Op::Operator(
+            Operator::Access(name)
+            | Operator::Assign(name)
+            | Operator::Constant(name)
+            | Operator::AssignOuter(name)
+            | Operator::AssignGlobal(name),
+        ) => name,
+        _ => return Json::Null,
+    };
+
+    let analysis = infer::analyse(&doc.program.body);
+    let text = match analysis.signatures.iter().find(|(_, n, _)| n == name) {
+        Some((_, _, sig)) if matches!(token.op, Op::Operator(Operator::Access(_))) => {
+            format!("#{} {}", name, sig)
+        }
+        _ => format!("variable {}", name),
+    };
+
+    Json::object(vec![
+        (
+            "contents",
+            Json::object(vec![
+                ("kind", Json::from("plaintext")),
+                ("value", Json::from(text)),
+            ]),
+        ),
+        ("range", doc.range(token.start, token.end + 1)),
+    ])
+}
+
+fn completion(doc: &Document) -> Json {
+    let mut items = Vec::<Json>::new();
+    let mut variables = Vec::new();
+    collect_variables(&doc.program.body, &mut variables);
+
+    for (name, _, constant) in variables {
+        let kind = match constant {
+            true => COMPLETION_CONSTANT,
+            false => COMPLETION_VARIABLE,
+        };
+        items.push(Json::object(vec![
+            ("label", Json::from(name)),
+            ("kind", Json::Int(kind)),
+        ]));
+    }
+    for (name, _, _) in doc.functions() {
+        items.push(Json::object(vec![
+            ("label", Json::from(name)),
+            ("kind", Json::Int(COMPLETION_FUNCTION)),
+        ]));
+    }
+
+    Json::Array(items)
+}
+
+fn document_symbols(doc: &Document) -> Json {
+    let mut symbols = Vec::<Json>::new();
+    for (name, start, close) in doc.functions() {
+        let end = close.map(|c| c + 1).unwrap_or(doc.lex.chars.len());
+        let name_end = start + name.chars().count() + 1;
+        symbols.push(Json::object(vec![
+            ("name", Json::from(name)),
+            ("kind", Json::Int(SYMBOL_FUNCTION)),
+            ("range", doc.range(start, end)),
+            ("selectionRange", doc.range(start, name_end)),
+        ]));
+    }
+
+    let mut variables = Vec::new();
+    collect_variables(&doc.program.body, &mut variables);
+    for (name, start, constant) in variables {
+        let (kind, sigil) = match constant {
+            true => (SYMBOL_CONSTANT, 2),
+            false => (SYMBOL_VARIABLE, 1),
+        };
+        let end = start + name.chars().count() + sigil;
+        symbols.push(Json::object(vec![
+            ("name", Json::from(name)),
+            ("kind", Json::Int(kind)),
+            ("range", doc.range(start, end)),
+            ("selectionRange", doc.range(start, end)),
+        ]));
+    }
+
+    Json::Array(symbols)
+}
+
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut documents = HashMap::<String, String>::new();
+
+    while let Some(message) = read_message(&mut input) {
+        let id = message.get("id");
+        let params = message.get("params");
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        //Requests about a document work on a fresh parse of its latest text
+        let document = |documents: &HashMap<String, String>| {
+            Document::new(documents.get(&uri).map_or("", |s| s.as_str()))
+        };
+
+        match message.get("method").as_str().unwrap_or("") {
+            "initialize" => respond(
+                id,
+                Json::object(vec![
+                    (
+                        "capabilities",
+                        Json::object(vec![
+                            ("textDocumentSync", Json::Int(1)),
+                            ("definitionProvider", Json::Bool(true)),
+                            ("hoverProvider", Json::Bool(true)),
+                            ("documentSymbolProvider", Json::Bool(true)),
+                            ("completionProvider", Json::object(vec![])),
+                        ]),
+                    ),
+                    (
+                        "serverInfo",
+                        Json::object(vec![("name", Json::from("aqua"))]),
+                    ),
+                ]),
+            )?,
+            "shutdown" => respond(id, Json::Null)?,
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .get("text")
+                    .as_str()
+                    .unwrap_or("");
+                documents.insert(uri.clone(), text.to_string());
+                publish_diagnostics(&uri, text)?;
+            }
+            "textDocument/didChange" => {
+                //Only full document sync is offered, so the last change is the whole text
+                if let Json::Array(changes) = params.get("contentChanges") {
+                    if let Some(text) = changes.last().and_then(|c| c.get("text").as_str()) {
+                        documents.insert(uri.clone(), text.to_string());
+                        publish_diagnostics(&uri, text)?;
+                    }
+                }
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                notify(
+                    "textDocument/publishDiagnostics",
+                    Json::object(vec![
+                        ("uri", Json::from(uri.as_str())),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]),
+                )?;
+            }
+            "textDocument/definition" => {
+                respond(id, definition(&mut document(&documents), &uri, params))?
+            }
+            "textDocument/hover" => respond(id, hover(&mut document(&documents), params))?,
+            "textDocument/completion" => respond(id, completion(&document(&documents)))?,
+            "textDocument/documentSymbol" => respond(id, document_symbols(&document(&documents)))?,
+            method => {
+                //Requests must always be answered, notifications can be ignored
+                if *id != Json::Null && !method.starts_with("$/") {
+                    send(Json::object(vec![
+                        ("jsonrpc", Json::from("2.0")),
+                        ("id", id.clone()),
+                        (
+                            "error",
+                            Json::object(vec![
+                                ("code", Json::Int(-32601)),
+                                ("message", Json::from(format!("Unknown method {}", method))),
+                            ]),
+                        ),
+                    ]))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,12 +1,30 @@
 #![allow(dead_code)]
 #![allow(unreachable_patterns)]
 
+mod check;
+mod compile;
+mod dump;
+mod format;
+#[cfg(test)]
+mod fuzz;
+mod infer;
+mod jit;
+mod json;
+mod lsp;
+mod parse;
+mod profile;
+mod trace;
+
 use std::collections::hash_map::HashMap;
 use std::env;
 use std::fmt;
 use std::io;
-use std::io::Read;
+use std::io::BufRead;
 use std::io::Write;
+use std::num::IntErrorKind;
+use std::process;
+use std::rc::Rc;
+use unicode_xid::UnicodeXID;
 
 //Enums - misc
 #[derive(Debug)]
@@ -14,6 +32,50 @@ enum Brace {
     Open,
     OpenFor(usize),
     OpenFunc(usize),
+    OpenCount {
+        pos: usize,
+        id: usize,
+        index: i32,
+        count: i32,
+    },
+    OpenEach {
+        pos: usize,
+        id: usize,
+        chars: Vec<char>,
+        index: usize,
+    },
+    OpenElseIf, //the condition of a ':?'
+    OpenTry {
+        body: usize,         //position of the '{' of the guarded block
+        stack: Vec<Operand>, //the stack as it was when the block was entered
+    },
+    OpenCase(usize), //a case of the match whose '{' is at the position
+    OpenWhileCond(usize),
+    OpenWhile {
+        cond: usize,
+        body: usize,
+    },
+}
+
+impl Brace {
+    //The first '{' of the loop this brace belongs to, which is what labels are attached to
+    fn loop_pos(&self) -> Option<usize> {
+        match self {
+            Brace::OpenFor(pos)
+            | Brace::OpenCount { pos, .. }
+            | Brace::OpenEach { pos, .. }
+            | Brace::OpenWhileCond(pos)
+            | Brace::OpenWhile { cond: pos, .. } => Some(*pos),
+            _ => None,
+        }
+    }
+}
+
+//Error from lexing or running a program, at a position in the Lexer's chars
+#[derive(Debug)]
+struct Error {
+    pos: usize,
+    msg: String,
 }
 
 //Enums - Op
@@ -21,7 +83,7 @@ enum Brace {
 enum Operand {
     Int(i32),
     Bool(bool),
-    String(String),
+    String(Rc<str>), //shared, so reading a variable does not copy the text
 }
 
 impl fmt::Display for Operand {
@@ -34,7 +96,17 @@ impl fmt::Display for Operand {
     }
 }
 
-#[derive(PartialEq)]
+impl Operand {
+    //As it would be written in a program, so that 1 and "1" can be told apart
+    fn literal(&self) -> String {
+        match self {
+            Operand::String(s) => format!("\"{}\"", s),
+            o => o.to_string(),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
 enum Operator {
     Add,
     Sub,
@@ -49,9 +121,15 @@ enum Operator {
     Pop,
     Clear,
     Assign(String),
+    Constant(String),     //=!name, binds a value that cannot be assigned to again
+    AssignOuter(String),  //=^name, assigns in the nearest scope that already has the name
+    AssignGlobal(String), //=^^name, assigns in the global scope
     Access(String),
     Index,
     Input,
+    Throw,
+    Assert,
+    AssertEq,
 }
 
 #[derive(PartialEq)]
@@ -59,8 +137,17 @@ enum Glyph {
     OpenSquiggle,
     CloseSquiggle,
     Loop,
-    Break,
+    Count(String),         //~name, runs its block once per number below the Int it pops
+    Each(String),          //~@name, runs its block once per character of the String it pops
+    While,                 //~?, runs its second block while its first leaves true
+    Label(String),         //@name, names the loop that follows
+    Break(Option<String>), //$ or $label
+    Continue(Option<String>), //^ or ^label, goes on to the next iteration
+    Return,                //$$, leaves the function from inside any loops
     Else,
+    ElseIf, //:?, runs its second block if its first leaves true and no branch before it ran
+    Match,  //??, runs the case whose literal equals the value it pops
+    Try,    //runs the second block after a !? if anything in the first throws or fails
     Define,
 }
 
@@ -71,6 +158,34 @@ enum Op {
     Glyph(Glyph),
 }
 
+//What the interpreter runs next: a token, or a token with a name Lexer::resolve has already
+//interned, with its id
+enum Step {
+    Op(Op),
+    Named(Named, usize),
+}
+
+//The tokens that carry a name
+#[derive(Clone, Copy)]
+enum Named {
+    Load,
+    Store,
+    Constant,
+    AssignOuter,
+    AssignGlobal,
+    Count,
+    Each,
+}
+
+//A name resolve() has interned, at the position of its token
+#[derive(Clone, Copy)]
+struct Resolved {
+    start: usize,
+    end: usize,
+    id: usize,
+    named: Named,
+}
+
 //Operation stack
 struct OpStack {
     stack: Vec<Operand>,
@@ -87,16 +202,24 @@ impl OpStack {
         self.stack.push(op);
     }
 
-    fn pop(&mut self) -> Operand {
+    fn pop(&mut self) -> Result<Operand, String> {
         match self.stack.pop() {
-            Some(o) => o,
-            None => panic!("Cannot pop from empty stack!"),
+            Some(o) => Ok(o),
+            None => Err("Cannot pop from empty stack!".to_string()),
+        }
+    }
+
+    fn pop_int(&mut self, err: &str) -> Result<i32, String> {
+        match self.pop()? {
+            Operand::Int(v) => Ok(v),
+            _ => Err(err.to_string()),
         }
     }
 
     //Operations
-    fn silent_pop(&mut self) {
-        self.pop();
+    fn silent_pop(&mut self) -> Result<(), String> {
+        self.pop()?;
+        Ok(())
     }
 
     fn clear(&mut self) {
@@ -104,186 +227,157 @@ impl OpStack {
     }
 
     //TODO: Some better way of checking types on operations, this is ugly
-    fn add(&mut self) {
+    fn add(&mut self) -> Result<(), String> {
+        let overflow = || "attempt to add with overflow".to_string();
+
         //Int Int
-        match self.pop() {
-            Operand::Int(v) => match self.pop() {
-                Operand::Int(v2) => self.push(Operand::Int(v + v2)),
-                Operand::String(v2) => self.push(Operand::String(v2 + &v.to_string())),
-                _ => panic!("Add only implemented for Int and String"),
+        match self.pop()? {
+            Operand::Int(v) => match self.pop()? {
+                Operand::Int(v2) => {
+                    self.push(Operand::Int(v.checked_add(v2).ok_or_else(overflow)?))
+                }
+                Operand::String(v2) => self.push(Operand::String(format!("{}{}", v2, v).into())),
+                _ => return Err("Add only implemented for Int and String".to_string()),
             },
-            Operand::String(v) => match self.pop() {
+            Operand::String(v) => match self.pop()? {
                 Operand::Int(v2) => {
                     let si = match v.trim().parse::<i32>() {
                         Ok(i) => i,
-                        Err(_) => panic!("Cannot parse string to int"),
+                        Err(_) => return Err("Cannot parse string to int".to_string()),
                     };
 
-                    self.push(Operand::Int(si + v2));
+                    self.push(Operand::Int(si.checked_add(v2).ok_or_else(overflow)?));
                 }
-                Operand::String(v2) => self.push(Operand::String(v2 + &v)),
-                Operand::Bool(v2) => self.push(Operand::String(v2.to_string() + &v)),
-                _ => panic!("Add only implemented for Int and String"),
+                Operand::String(v2) => self.push(Operand::String(format!("{}{}", v2, v).into())),
+                Operand::Bool(v2) => self.push(Operand::String(format!("{}{}", v2, v).into())),
             },
-            Operand::Bool(v) => match self.pop() {
-                Operand::String(v2) => self.push(Operand::String(v2 + &v.to_string())),
-                _ => panic!("Can only append Bools to Strings!"),
+            Operand::Bool(v) => match self.pop()? {
+                Operand::String(v2) => self.push(Operand::String(format!("{}{}", v2, v).into())),
+                _ => return Err("Can only append Bools to Strings!".to_string()),
             },
-            _ => panic!("Add only implemented for Int and String"),
         };
+
+        Ok(())
     }
 
-    fn sub(&mut self) {
+    fn sub(&mut self) -> Result<(), String> {
         //Int Int
-        let i = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Sub only implemented for int"),
-        };
-
-        let i2 = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Sub only implemented for int"),
-        };
+        let i = self.pop_int("Sub only implemented for int")?;
+        let i2 = self.pop_int("Sub only implemented for int")?;
 
-        self.push(Operand::Int(i2 - i));
+        match i2.checked_sub(i) {
+            Some(v) => self.push(Operand::Int(v)),
+            None => return Err("attempt to subtract with overflow".to_string()),
+        }
+        Ok(())
     }
 
-    fn mul(&mut self) {
+    fn mul(&mut self) -> Result<(), String> {
         //Int Int
-        let i = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Mul only implemented for int"),
-        };
-
-        let i2 = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Mul only implemented for int"),
-        };
+        let i = self.pop_int("Mul only implemented for int")?;
+        let i2 = self.pop_int("Mul only implemented for int")?;
 
-        self.push(Operand::Int(i * i2));
+        match i.checked_mul(i2) {
+            Some(v) => self.push(Operand::Int(v)),
+            None => return Err("attempt to multiply with overflow".to_string()),
+        }
+        Ok(())
     }
 
-    fn div(&mut self) {
+    fn div(&mut self) -> Result<(), String> {
         //Int Int
-        let i = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Mul only implemented for int"),
-        };
-
-        let i2 = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Mul only implemented for int"),
-        };
+        let i = self.pop_int("Mul only implemented for int")?;
+        let i2 = self.pop_int("Mul only implemented for int")?;
 
-        self.push(Operand::Int(i2 / i));
+        match i2.checked_div(i) {
+            Some(v) => self.push(Operand::Int(v)),
+            None if i == 0 => return Err("attempt to divide by zero".to_string()),
+            None => return Err("attempt to divide with overflow".to_string()),
+        }
+        Ok(())
     }
 
-    fn equal(&mut self) {
+    fn equal(&mut self) -> Result<(), String> {
         //Int Int || Bool Bool
-        match self.pop() {
-            Operand::Int(i) => match self.pop() {
+        match self.pop()? {
+            Operand::Int(i) => match self.pop()? {
                 Operand::Int(i2) => self.push(Operand::Bool(i == i2)),
-                _ => panic!("Can only compare Int with Int!"),
+                _ => return Err("Can only compare Int with Int!".to_string()),
             },
 
-            Operand::Bool(v) => match self.pop() {
+            Operand::Bool(v) => match self.pop()? {
                 Operand::Bool(v2) => self.push(Operand::Bool(v == v2)),
-                _ => panic!("Can only compare Bool with Bool!"),
+                _ => return Err("Can only compare Bool with Bool!".to_string()),
             },
 
-            Operand::String(v) => match self.pop() {
+            Operand::String(v) => match self.pop()? {
                 Operand::String(v2) => self.push(Operand::Bool(v == v2)),
-                _ => panic!("Can only compare String with String!"),
+                _ => return Err("Can only compare String with String!".to_string()),
             },
-
-            _ => panic!("Can only compare Ints and Bools!"),
         }
-    }
-
-    fn not_equal(&mut self) {
-        //Int Int || Bool Bool
-        match self.pop() {
-            Operand::Int(i) => match self.pop() {
-                Operand::Int(i2) => self.push(Operand::Bool(i != i2)),
-                _ => panic!("Can only compare Int with Int!"),
-            },
-
-            Operand::Bool(v) => match self.pop() {
-                Operand::Bool(v2) => self.push(Operand::Bool(v != v2)),
-                _ => panic!("Can only compare Bool with Bool!"),
-            },
 
-            Operand::String(v) => match self.pop() {
-                Operand::String(v2) => self.push(Operand::Bool(v != v2)),
-                _ => panic!("Can only compare String with String!"),
-            },
+        Ok(())
+    }
 
-            _ => panic!("Can only compare Ints and Bools!"),
+    fn not_equal(&mut self) -> Result<(), String> {
+        self.equal()?;
+        if let Some(Operand::Bool(v)) = self.stack.last_mut() {
+            *v = !*v;
         }
+
+        Ok(())
     }
 
-    fn less_than(&mut self) {
+    fn less_than(&mut self) -> Result<(), String> {
         //Int Int
-        let i = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Can only compare Ints!"),
-        };
+        let i = self.pop_int("Can only compare Ints!")?;
+        let v = self.pop_int("Can only compare Ints!")?;
 
-        match self.pop() {
-            Operand::Int(v) => self.push(Operand::Bool(v < i)),
-            _ => panic!("Can only compare Ints!"),
-        };
+        self.push(Operand::Bool(v < i));
+        Ok(())
     }
 
-    fn greater_than(&mut self) {
+    fn greater_than(&mut self) -> Result<(), String> {
         //Int Int
-        let i = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Can only compare Ints!"),
-        };
+        let i = self.pop_int("Can only compare Ints!")?;
+        let v = self.pop_int("Can only compare Ints!")?;
 
-        match self.pop() {
-            Operand::Int(v) => self.push(Operand::Bool(v > i)),
-            _ => panic!("Can only compare Ints!"),
-        };
+        self.push(Operand::Bool(v > i));
+        Ok(())
     }
 
-    fn cond(&mut self, lex: &mut Lexer) {
-        match self.pop() {
-            Operand::Bool(v) => {
-                //on false, skip to end
-                if !v {
-                    lex.exit_body();
-                    //If the next token is an else, execute it
-                    if let Some(Op::Glyph(Glyph::Else)) = lex.peek() {
-                        lex.next();
-                    }
-                }
-            }
-            _ => panic!("Conditional requires Bool at top of stack!"),
+    //Whether the block after the '?' runs
+    fn cond(&mut self) -> Result<bool, String> {
+        match self.pop()? {
+            Operand::Bool(v) => Ok(v),
+            _ => Err("Conditional requires Bool at top of stack!".to_string()),
         }
     }
 
-    fn print(&mut self) {
-        if self.stack.is_empty() {
-            println!("_");
+    fn print(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        let result = if self.stack.is_empty() {
+            writeln!(out, "_")
         } else {
-            print!("{}", self.stack[self.stack.len() - 1]);
-            io::stdout().flush().unwrap();
-        }
+            write!(out, "{}", self.stack[self.stack.len() - 1]).and_then(|_| out.flush())
+        };
+
+        result.map_err(|e| format!("Failed to write output: {}", e))
     }
 
-    fn assign(&mut self, var_store: &mut VarStore, name: String) {
-        if self.stack.is_empty() {
-            panic!("Cannot assign from empty stack!");
-        }
+    fn assign(&mut self, var_store: &mut VarStore, id: usize) -> Result<(), String> {
+        let val = self.pop_assigned()?;
+        var_store.set_var(id, val)
+    }
 
-        let val = self.pop();
-        var_store.set_var(name, val);
+    //The value for an assignment of any kind
+    fn pop_assigned(&mut self) -> Result<Operand, String> {
+        self.stack
+            .pop()
+            .ok_or_else(|| "Cannot assign from empty stack!".to_string())
     }
 
-    fn access(&mut self, var_store: &mut VarStore, name: &str) -> bool {
-        match var_store.get_var(name) {
+    fn access(&mut self, var_store: &mut VarStore, id: usize) -> bool {
+        match var_store.get_var(id) {
             Some(v) => {
                 self.push(v);
                 true
@@ -292,79 +386,281 @@ impl OpStack {
         }
     }
 
-    fn get_index(&mut self) {
-        let index = match self.pop() {
-            Operand::Int(v) => v,
-            _ => panic!("Index must be an Int!"),
-        };
+    //Strings are indexed by Unicode scalar value, not by grapheme cluster, so an 'é' written
+    //as 'e' and a combining accent takes two indices while a precomposed 'é' takes one
+    fn get_index(&mut self) -> Result<(), String> {
+        let index = self.pop_int("Index must be an Int!")?;
 
-        match self.pop() {
+        match self.pop()? {
             Operand::String(v) => {
                 self.push(Operand::String(match v.chars().nth(index as usize) {
-                    Some(c) => c.to_string(),
-                    None => panic!("Index out of bounds!"),
+                    Some(c) => c.to_string().into(),
+                    None => return Err("Index out of bounds!".to_string()),
                 }));
             }
-            _ => panic!("Can only index Strings!"),
+            _ => return Err("Can only index Strings!".to_string()),
         };
+
+        Ok(())
+    }
+
+    fn assert(&mut self) -> Result<(), String> {
+        match self.pop()? {
+            Operand::Bool(true) => Ok(()),
+            Operand::Bool(false) => Err("Assertion failed!".to_string()),
+            _ => Err("Assert requires a Bool!".to_string()),
+        }
+    }
+
+    //Values of different types are never equal, rather than an error
+    fn assert_eq(&mut self) -> Result<(), String> {
+        let right = self.pop()?;
+        let left = self.pop()?;
+
+        match left == right {
+            true => Ok(()),
+            false => Err(format!(
+                "Assertion failed: {} != {}",
+                left.literal(),
+                right.literal()
+            )),
+        }
     }
 
-    fn read_input(&mut self) {
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line");
+    fn read_input(&mut self, input: &mut dyn BufRead) -> Result<(), String> {
+        let mut line = String::new();
+        if input.read_line(&mut line).is_err() {
+            return Err("Failed to read line".to_string());
+        }
 
-        self.push(Operand::String(input));
+        self.push(Operand::String(line.into()));
+        Ok(())
     }
 }
 
-//Variable storage
+//Where a function's variables go in the scopes of its calls. Lexer::resolve gives a slot to
+//each name the function's body can assign before anything runs, so a scope only has room for
+//the names its own function uses
+#[derive(Default)]
+struct Layout {
+    slots: Vec<Option<usize>>, //slot of each name, by id
+    len: usize,
+}
+
+//Variable storage. Names are interned to ids before running, and each scope holds its
+//variables by slot, so looking one up is a couple of indexes into each scope rather than a hash
 struct VarStore {
-    vars: Vec<HashMap<String, Operand>>,
+    vars: Vec<Vec<Option<Operand>>>,
+    consts: Vec<Vec<Option<usize>>>, //where each scope's constants were defined
+    layouts: Vec<usize>,             //index into `functions` of each scope's layout
+    functions: Vec<Layout>, //the top level's first, then one for each function name interned
     funcs: HashMap<String, usize>,
+    names: Vec<String>,                 //name of each id
+    ids: HashMap<String, usize>,        //id of each name
+    calls: Vec<Option<(usize, usize)>>, //position and layout of the function each id names, if there is one
+    frames: Vec<usize>, //index of the first scope of each function call, the global one first
+    lexical: bool, //only the current function's and the global scopes can be seen, rather than every caller's
+    block_scopes: bool, //every block gets a scope, rather than only function calls
 }
 
 impl VarStore {
     fn new() -> VarStore {
         let mut vs = VarStore {
-            vars: Vec::<HashMap<String, Operand>>::new(),
+            vars: Vec::<Vec<Option<Operand>>>::new(),
+            consts: Vec::<Vec<Option<usize>>>::new(),
+            layouts: Vec::<usize>::new(),
+            functions: vec![Layout::default()],
             funcs: HashMap::<String, usize>::new(),
+            names: Vec::<String>::new(),
+            ids: HashMap::<String, usize>::new(),
+            calls: Vec::<Option<(usize, usize)>>::new(),
+            frames: Vec::<usize>::new(),
+            lexical: false,
+            block_scopes: false,
         };
 
-        vs.vars.push(HashMap::<String, Operand>::new());
+        vs.new_scope(0);
         vs
     }
 
-    fn get_var(&self, name: &str) -> Option<Operand> {
-        for i in (0..self.vars.len()).rev() {
-            match self.vars[i].get(name) {
-                Some(v) => return Some((*v).clone()),
-                None => continue,
+    //The id of the name, giving it one if it has none yet. Functions must be registered first
+    fn intern(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+
+        let call = self.funcs.get(name).map(|&pos| {
+            self.functions.push(Layout::default());
+            (pos, self.functions.len() - 1)
+        });
+        self.names.push(name.to_string());
+        self.calls.push(call);
+        self.ids.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    //Index of the current function's scope, below any of its blocks'
+    fn frame(&self) -> usize {
+        self.frames.last().copied().unwrap_or(0)
+    }
+
+    //Indices of the scopes names are looked up in, innermost first
+    fn visible(&self) -> impl Iterator<Item = usize> + '_ {
+        let frame = self.frame();
+        (0..self.vars.len())
+            .rev()
+            .filter(move |&i| !self.lexical || i >= frame || i == 0)
+    }
+
+    //Where the name goes in the scope at index i, if its function has given it a slot
+    fn slot(&self, i: usize, id: usize) -> Option<usize> {
+        self.functions[self.layouts[i]]
+            .slots
+            .get(id)
+            .copied()
+            .flatten()
+    }
+
+    //Give the name a slot in the layout, if it has none yet. Only resolve() does this, as
+    //running the program only indexes into the slots it gave out
+    fn reserve(&mut self, layout: usize, id: usize) {
+        let layout = &mut self.functions[layout];
+        if layout.slots.len() <= id {
+            layout.slots.resize(id + 1, None);
+        }
+        if layout.slots[id].is_none() {
+            layout.slots[id] = Some(layout.len);
+            layout.len += 1;
+        }
+    }
+
+    //The value of the name in the scope at index i, if it has one
+    fn get(&self, i: usize, id: usize) -> Option<&Operand> {
+        self.vars[i][self.slot(i, id)?].as_ref()
+    }
+
+    //Fails if resolve() gave the name no slot in the scope's function, which it does for every
+    //name the function can assign
+    fn put(&mut self, i: usize, id: usize, val: Operand) -> Result<(), String> {
+        match self.slot(i, id) {
+            Some(slot) => {
+                self.vars[i][slot] = Some(val);
+                Ok(())
             }
+            None => Err(format!("Cannot assign to '{}' here!", self.names[id])),
         }
+    }
 
-        None
+    //Where the constant with the name was defined, if there is one in a visible scope
+    fn constant(&self, id: usize) -> Option<usize> {
+        self.visible()
+            .find_map(|i| self.consts[i].get(self.slot(i, id)?).copied().flatten())
     }
 
-    fn set_var(&mut self, name: String, val: Operand) {
+    //A scope has no room for constants until its first one
+    fn set_constant(&mut self, id: usize, val: Operand, pos: usize) -> Result<(), String> {
         let i = self.vars.len() - 1;
-        self.vars[i].insert(name, val);
+        self.put(i, id, val)?;
+        let slot = self.slot(i, id).unwrap_or_default();
+        let len = self.vars[i].len();
+        let consts = &mut self.consts[i];
+        consts.resize(len, None);
+        consts[slot] = Some(pos);
+        Ok(())
     }
 
-    fn new_scope(&mut self) {
-        self.vars.push(HashMap::<String, Operand>::new());
+    fn get_var(&self, id: usize) -> Option<Operand> {
+        self.visible().find_map(|i| self.get(i, id).cloned())
     }
 
+    //With block scopes, a variable of an enclosing block of the same function is updated
+    //rather than shadowed
+    fn set_var(&mut self, id: usize, val: Operand) -> Result<(), String> {
+        let last = self.vars.len() - 1;
+        let i = match self.block_scopes {
+            true => (self.frame()..last)
+                .rev()
+                .find(|&i| self.get(i, id).is_some())
+                .unwrap_or(last),
+            false => last,
+        };
+        self.put(i, id, val)
+    }
+
+    //Returns false, leaving every scope alone, if no visible scope has the name
+    fn set_outer(&mut self, id: usize, val: Operand) -> bool {
+        let scope = self.visible().find(|&i| self.get(i, id).is_some());
+        match scope {
+            Some(i) => self.put(i, id, val).is_ok(),
+            None => false,
+        }
+    }
+
+    fn set_global(&mut self, id: usize, val: Operand) -> Result<(), String> {
+        self.put(0, id, val)
+    }
+
+    //A scope with a slot for each name in the layout
+    fn push_scope(&mut self, layout: usize) {
+        self.vars.push(vec![None; self.functions[layout].len]);
+        self.consts.push(Vec::<Option<usize>>::new());
+        self.layouts.push(layout);
+    }
+
+    //The scope of a call to the function with the layout
+    fn new_scope(&mut self, layout: usize) {
+        self.frames.push(self.vars.len());
+        self.push_scope(layout);
+    }
+
+    //A block's scope shares its function's layout
+    fn new_block_scope(&mut self) {
+        self.push_scope(self.layouts[self.vars.len() - 1]);
+    }
+
+    //The global scope is never destroyed, even by stray closing braces
     fn destroy_scope(&mut self) {
-        self.vars.pop();
+        if self.vars.len() > 1 {
+            self.vars.pop();
+            self.consts.pop();
+            self.layouts.pop();
+            if self.frame() == self.vars.len() {
+                self.frames.pop();
+            }
+        }
     }
 }
 
+//Words read as keywords wherever a name could be. 'assert-eq' is one too, read on from
+//'assert', as '-' is otherwise never part of a name. They are reserved: assigning to one,
+//looping over one or defining a function named one is an error, as it could never be read back
+const KEYWORDS: [&str; 3] = ["true", "false", "assert"];
+
+fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name)
+}
+
+fn keyword_error(name: &str) -> String {
+    format!("'{}' is a keyword and cannot be used as a name!", name)
+}
+
+//A name token the interpreter met without resolve() having interned it, which only happens
+//if resolve() read the file differently from the interpreter
+fn unresolved(name: &str) -> String {
+    format!("Name '{}' was not resolved before running!", name)
+}
+
 //Lexer
 struct Lexer {
     chars: Vec<char>,
     current: usize,
+    start: usize, //position of the first character of the last token returned by next()
+    error: Option<Error>, //set when next() meets something it cannot lex, it returns None from then on
+    //Modes from their flags, or a '//aqua: lexical' or '//aqua: block-scopes' line before the first token
+    lexical: bool,
+    block_scopes: bool,
+    resolved: Vec<Resolved>, //in order of position, empty until resolve()
+    hint: usize,             //index into resolved of the next name along, see resolution()
 }
 
 impl Lexer {
@@ -372,13 +668,27 @@ impl Lexer {
         let mut l = Lexer {
             chars: Vec::<char>::new(),
             current: 0,
+            start: 0,
+            error: None,
+            lexical: false,
+            block_scopes: false,
+            resolved: Vec::new(),
+            hint: 0,
+        };
+        let pragma = |name: &str| {
+            file.lines()
+                .map(str::trim)
+                .take_while(|line| line.is_empty() || line.starts_with("//"))
+                .any(|line| line.strip_prefix("//aqua: ") == Some(name))
         };
+        l.lexical = pragma("lexical");
+        l.block_scopes = pragma("block-scopes");
         l.chars = file.chars().collect();
         l
     }
 
     //Locate function definitions, and store their positions
-    fn register_functions(&mut self, var_store: &mut VarStore) {
+    fn register_functions(&mut self, var_store: &mut VarStore) -> Result<(), Error> {
         loop {
             self.current += 1;
             if self.current >= self.chars.len() {
@@ -389,12 +699,36 @@ impl Lexer {
 
             match c {
                 _c if self.is_str("//") => self.comment(),
+                _c if self.is_str("/*") => self.multi_line_comment(),
+                //Strings and names are skipped whole, so that a '#' in one is not taken for a
+                //definition. A bad string is left for the 2nd pass to report
+                '"' | '\'' => {
+                    self.read_str(false);
+                    self.error = None;
+                }
+                'r' if matches!(self.chars.get(self.current + 1), Some('"' | '\'')) => {
+                    self.current += 1;
+                    self.read_str(true);
+                    self.error = None;
+                }
+                c if c.is_xid_start() => {
+                    self.read_name(0);
+                }
                 '#' => {
-                    let fn_name = self.read_until_space(1);
-                    self.seek(
+                    let pos = self.current;
+                    let fn_name = self.read_name(1);
+                    if is_keyword(&fn_name) {
+                        return Err(Error {
+                            pos,
+                            msg: keyword_error(&fn_name),
+                        });
+                    }
+                    if let Err(msg) = self.seek(
                         Op::Glyph(Glyph::OpenSquiggle),
                         "Function definition must start with '{'!",
-                    );
+                    ) {
+                        return Err(self.error.take().unwrap_or(Error { pos, msg }));
+                    }
                     var_store.funcs.insert(fn_name, self.current);
                 }
                 _ => (),
@@ -402,9 +736,148 @@ impl Lexer {
         }
         //Reset current, ready for the 2nd pass
         self.current = 0;
+        self.error = None;
+        Ok(())
+    }
+
+    //Intern every name, and give each function a slot for every name its body assigns, so that
+    //running the program only indexes into scopes. Functions must be registered first.
+    //A token that fails to lex is skipped, to be reported if the program gets that far
+    fn resolve(&mut self, var_store: &mut VarStore) {
+        let mut resolved = Vec::<Resolved>::new();
+        let mut bodies = Vec::<(usize, usize)>::new(); //layout and brace depth of each function body being read
+        let mut define = false; //the last token was a '#'
+        let mut body = None; //layout of the function whose body opens next
+        let mut depth = 0usize;
+        loop {
+            let op = match self.next() {
+                Some(op) => op,
+                None if self.error.take().is_some() => continue,
+                None => break,
+            };
+            let layout = bodies.last().map_or(0, |&(layout, _)| layout);
+            let after_define = std::mem::take(&mut define);
+            let (name, named) = match op {
+                //The name after a '#' is the function's, not a read
+                Op::Operator(Operator::Access(name)) if after_define => {
+                    let id = var_store.intern(&name);
+                    body = var_store.calls[id].map(|(_, layout)| layout);
+                    continue;
+                }
+                Op::Glyph(Glyph::Define) => {
+                    define = true;
+                    continue;
+                }
+                Op::Glyph(Glyph::OpenSquiggle) => {
+                    depth += 1;
+                    if let Some(layout) = body.take() {
+                        bodies.push((layout, depth));
+                    }
+                    continue;
+                }
+                Op::Glyph(Glyph::CloseSquiggle) => {
+                    if bodies.last().is_some_and(|&(_, d)| d == depth) {
+                        bodies.pop();
+                    }
+                    depth = depth.saturating_sub(1);
+                    continue;
+                }
+                Op::Operator(Operator::Access(name)) => (name, Named::Load),
+                Op::Operator(Operator::Assign(name)) => (name, Named::Store),
+                Op::Operator(Operator::Constant(name)) => (name, Named::Constant),
+                Op::Operator(Operator::AssignOuter(name)) => (name, Named::AssignOuter),
+                Op::Operator(Operator::AssignGlobal(name)) => (name, Named::AssignGlobal),
+                Op::Glyph(Glyph::Count(name)) => (name, Named::Count),
+                Op::Glyph(Glyph::Each(name)) => (name, Named::Each),
+                _ => continue,
+            };
+
+            let id = var_store.intern(&name);
+            //A read needs no slot, and '=^' only assigns where the name already has one
+            match named {
+                Named::Load | Named::AssignOuter => (),
+                Named::AssignGlobal => var_store.reserve(0, id),
+                _ => var_store.reserve(layout, id),
+            }
+            resolved.push(Resolved {
+                start: self.start,
+                end: self.current,
+                id,
+                named,
+            });
+        }
+
+        //The global scope was made before there were any slots
+        var_store.vars[0].resize(var_store.functions[0].len, None);
+        self.resolved = resolved;
+        self.current = 0;
+        self.error = None;
+    }
+
+    //Like next(), but a token with a name resolve() has interned comes back with its id
+    fn next_step(&mut self) -> Option<Step> {
+        if self.resolved.is_empty() || self.error.is_some() {
+            return self.next().map(Step::Op);
+        }
+
+        //Skip what next() would, to find where the next token starts
+        loop {
+            self.current += 1;
+            if self.current >= self.chars.len() {
+                return None;
+            }
+            if self.is_str("//") {
+                self.comment();
+            } else if self.is_str("/*") {
+                self.multi_line_comment();
+            } else if !self.chars[self.current].is_whitespace() {
+                break;
+            }
+        }
+        self.current -= 1;
+
+        match self.resolution(self.current + 1) {
+            Some(r) => {
+                self.start = r.start;
+                self.current = r.end;
+                Some(Step::Named(r.named, r.id))
+            }
+            None => self.next().map(Step::Op),
+        }
+    }
+
+    //The name resolved at pos, if there is one. Running mostly moves forwards, so only a jump
+    //needs a search
+    fn resolution(&mut self, pos: usize) -> Option<Resolved> {
+        let behind = self.hint > 0 && self.resolved[self.hint - 1].start >= pos;
+        let ahead = self.resolved.get(self.hint).is_some_and(|r| r.start < pos);
+        if behind || ahead {
+            self.hint = self.resolved.partition_point(|r| r.start < pos);
+        }
+
+        match self.resolved.get(self.hint) {
+            Some(r) if r.start == pos => {
+                self.hint += 1;
+                Some(*r)
+            }
+            _ => None,
+        }
+    }
+
+    //Record an error at the current token, ending the token stream
+    fn fail<T>(&mut self, msg: String) -> Option<T> {
+        self.error = Some(Error {
+            pos: self.start,
+            msg,
+        });
+        None
     }
 
     fn next(&mut self) -> Option<Op> {
+        if self.error.is_some() {
+            return None;
+        }
+
         loop {
             self.current += 1;
             if self.current >= self.chars.len() {
@@ -412,6 +885,7 @@ impl Lexer {
             }
 
             let c = self.chars[self.current];
+            self.start = self.current;
             /*if !c.is_ascii_whitespace(){
                 println!("{}", c);
             }*/
@@ -422,14 +896,27 @@ impl Lexer {
                 _c if self.is_str("/*") => self.multi_line_comment(),
 
                 //Operands
-                c if c.is_ascii_digit() => return Some(Op::Operand(Operand::Int(self.read_num()))),
-                _c if self.is_str("true") => return Some(Op::Operand(Operand::Bool(true))),
-                _c if self.is_str("false") => return Some(Op::Operand(Operand::Bool(false))),
-                '"' | '\'' => return Some(Op::Operand(Operand::String(self.read_str()))),
+                //A '-' directly followed by a digit starts a negative literal, otherwise it is Sub
+                c if c.is_ascii_digit() || (c == '-' && self.next_is_digit()) => {
+                    return self.read_num().map(|i| Op::Operand(Operand::Int(i)))
+                }
+                '"' | '\'' => {
+                    return self
+                        .read_str(false)
+                        .map(|s| Op::Operand(Operand::String(s.into())))
+                }
+                'r' if matches!(self.chars.get(self.current + 1), Some('"' | '\'')) => {
+                    self.current += 1;
+                    return self
+                        .read_str(true)
+                        .map(|s| Op::Operand(Operand::String(s.into())));
+                }
 
                 //Operators
                 _c if self.is_str("==") => return Some(Op::Operator(Operator::Equal)),
                 _c if self.is_str("!=") => return Some(Op::Operator(Operator::NotEqual)),
+                _c if self.is_str("!?") => return Some(Op::Glyph(Glyph::Try)),
+                '!' => return Some(Op::Operator(Operator::Throw)),
                 _c if self.is_str("[]") => return Some(Op::Operator(Operator::Index)),
                 '<' => return Some(Op::Operator(Operator::LessThan)),
                 '>' => return Some(Op::Operator(Operator::GreaterThan)),
@@ -438,28 +925,93 @@ impl Lexer {
                 '.' => return Some(Op::Operator(Operator::Print)),
                 '*' => return Some(Op::Operator(Operator::Mul)),
                 '/' => return Some(Op::Operator(Operator::Div)),
+                _c if self.is_str("??") => return Some(Op::Glyph(Glyph::Match)),
                 '?' => return Some(Op::Operator(Operator::Cond)),
                 ',' => return Some(Op::Operator(Operator::Pop)),
                 ';' => return Some(Op::Operator(Operator::Clear)),
-                '=' => return Some(Op::Operator(Operator::Assign(self.read_until_space(1)))),
+                '=' if self.chars.get(self.current + 1) == Some(&'!') => match self.read_name(2) {
+                    name if is_keyword(&name) => return self.fail(keyword_error(&name)),
+                    name if name.starts_with(|c: char| c.is_xid_start()) => {
+                        return Some(Op::Operator(Operator::Constant(name)))
+                    }
+                    _ => return self.fail("Constant must be followed by a name!".to_string()),
+                },
+                '=' if self.chars.get(self.current + 1) == Some(&'^') => {
+                    let global = self.chars.get(self.current + 2) == Some(&'^');
+                    match self.read_name(if global { 3 } else { 2 }) {
+                        name if !name.starts_with(|c: char| c.is_xid_start()) => {
+                            return self.fail("Assignment must be followed by a name!".to_string())
+                        }
+                        name if is_keyword(&name) => return self.fail(keyword_error(&name)),
+                        name if global => return Some(Op::Operator(Operator::AssignGlobal(name))),
+                        name => return Some(Op::Operator(Operator::AssignOuter(name))),
+                    }
+                }
+                '=' => match self.read_name(1) {
+                    name if is_keyword(&name) => return self.fail(keyword_error(&name)),
+                    name if name.starts_with(|c: char| c.is_xid_start()) => {
+                        return Some(Op::Operator(Operator::Assign(name)))
+                    }
+                    _ => return self.fail("Assignment must be followed by a name!".to_string()),
+                },
                 '_' => return Some(Op::Operator(Operator::Input)),
 
                 //Glyphs
                 '{' => return Some(Op::Glyph(Glyph::OpenSquiggle)),
                 '}' => return Some(Op::Glyph(Glyph::CloseSquiggle)),
-                '~' => return Some(Op::Glyph(Glyph::Loop)),
-                '$' => return Some(Op::Glyph(Glyph::Break)),
+                '~' => match self.chars.get(self.current + 1) {
+                    Some('?') => {
+                        self.current += 1;
+                        return Some(Op::Glyph(Glyph::While));
+                    }
+                    Some('@') => match self.read_name(2) {
+                        name if is_keyword(&name) => return self.fail(keyword_error(&name)),
+                        name if name.starts_with(|c: char| c.is_xid_start()) => {
+                            return Some(Op::Glyph(Glyph::Each(name)))
+                        }
+                        _ => {
+                            return self
+                                .fail("For-each loop must be followed by a name!".to_string())
+                        }
+                    },
+                    Some(c) if c.is_xid_start() => match self.read_name(1) {
+                        name if is_keyword(&name) => return self.fail(keyword_error(&name)),
+                        name => return Some(Op::Glyph(Glyph::Count(name))),
+                    },
+                    _ => return Some(Op::Glyph(Glyph::Loop)),
+                },
+                _c if self.is_str("$$") => return Some(Op::Glyph(Glyph::Return)),
+                '$' => return Some(Op::Glyph(Glyph::Break(self.read_label()))),
+                '^' => return Some(Op::Glyph(Glyph::Continue(self.read_label()))),
+                '@' => match self.read_name(1) {
+                    name if name.starts_with(|c: char| c.is_xid_start()) => {
+                        return Some(Op::Glyph(Glyph::Label(name)))
+                    }
+                    _ => return self.fail("Label must be followed by a name!".to_string()),
+                },
+                _c if self.is_str(":?") => return Some(Op::Glyph(Glyph::ElseIf)),
                 ':' => return Some(Op::Glyph(Glyph::Else)),
                 '#' => return Some(Op::Glyph(Glyph::Define)),
 
-                //Variable access
-                c if c.is_ascii_alphabetic() => {
-                    return Some(Op::Operator(Operator::Access(self.read_until_space(0))))
-                }
+                //Variable access, and the keywords that look like names, see KEYWORDS
+                c if c.is_xid_start() => match self.read_name(0).as_str() {
+                    "true" => return Some(Op::Operand(Operand::Bool(true))),
+                    "false" => return Some(Op::Operand(Operand::Bool(false))),
+                    //The only name with a '-' in it
+                    "assert" => {
+                        let end = self.current;
+                        if self.is_str("t-eq") && !self.next_is_name() {
+                            return Some(Op::Operator(Operator::AssertEq));
+                        }
+                        self.current = end;
+                        return Some(Op::Operator(Operator::Assert));
+                    }
+                    name => return Some(Op::Operator(Operator::Access(name.to_string()))),
+                },
 
                 //Misc
-                c if c.is_ascii_whitespace() => (),
-                _ => panic!("Unrecognised token {}", c),
+                c if c.is_whitespace() => (),
+                _ => return self.fail(format!("Unrecognised token {}", c)),
             };
         }
     }
@@ -471,60 +1023,186 @@ impl Lexer {
         op
     }
 
-    fn read_num(&mut self) -> i32 {
-        let mut num = String::new();
-        while self.current < self.chars.len() && self.chars[self.current].is_ascii_digit() {
-            num.push(self.chars[self.current]);
+    //The label directly after a '$' or '^', if there is one
+    fn read_label(&mut self) -> Option<String> {
+        match self.chars.get(self.current + 1) {
+            Some(c) if c.is_xid_start() => Some(self.read_name(1)),
+            _ => None,
+        }
+    }
+
+    fn next_is_name(&self) -> bool {
+        self.chars
+            .get(self.current + 1)
+            .is_some_and(|c| c.is_xid_continue())
+    }
+
+    fn next_is_digit(&self) -> bool {
+        self.chars
+            .get(self.current + 1)
+            .is_some_and(|c| c.is_ascii_digit())
+    }
+
+    //Decimal, or hex, binary and octal after a 0x, 0b or 0o prefix.
+    //Any of them can have a leading '-', and '_' separators between the digits
+    fn read_num(&mut self) -> Option<i32> {
+        let start = self.current;
+        let negative = self.chars[self.current] == '-';
+        if negative {
+            self.current += 1;
+        }
+
+        let radix = match (self.chars[self.current], self.chars.get(self.current + 1)) {
+            ('0', Some('x' | 'X')) => 16,
+            ('0', Some('b' | 'B')) => 2,
+            ('0', Some('o' | 'O')) => 8,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.current += 2;
+        }
+
+        //Letters are only taken as digits after a prefix, so "5x" is still 5 then x
+        let mut num = String::from(if negative { "-" } else { "" });
+        while let Some(&c) = self.chars.get(self.current) {
+            if c.is_ascii_digit() || (radix != 10 && c.is_ascii_alphanumeric()) {
+                num.push(c);
+            } else if c != '_' {
+                break;
+            }
             self.current += 1;
         }
 
         self.current -= 1;
-        num.parse().unwrap()
+        let text: String = self.chars[start..=self.current].iter().collect();
+        match i32::from_str_radix(&num, radix) {
+            Ok(i) => Some(i),
+            Err(e) => match e.kind() {
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                    self.fail(format!("Integer literal {} is out of range!", text))
+                }
+                IntErrorKind::Empty | IntErrorKind::InvalidDigit
+                    if num.len() == negative as usize =>
+                {
+                    self.fail(format!("Integer literal {} has no digits!", text))
+                }
+                _ => self.fail(format!("Invalid digit in integer literal {}!", text)),
+            },
+        }
     }
 
-    fn read_str(&mut self) -> String {
+    //Strings are quoted with " or ', or with three of either to span several lines.
+    //Raw strings, prefixed with r, keep backslashes as they are
+    fn read_str(&mut self, raw: bool) -> Option<String> {
         let mut str = String::new();
         let symbol = self.chars[self.current]; //make sure we know which character terminates the string
+        let triple = self.chars.get(self.current + 1) == Some(&symbol)
+            && self.chars.get(self.current + 2) == Some(&symbol);
+        if triple {
+            self.current += 2;
+        }
 
-        self.current += 1;
-        let mut c = self.chars[self.current];
+        loop {
+            self.current += 1;
+            let c = match self.chars.get(self.current).copied() {
+                Some(c) if c == symbol && !triple => break,
+                Some(c) if c == symbol && self.is_str(&symbol.to_string().repeat(3)) => break,
+                Some(c) => c,
+                None => return self.fail("Unterminated string!".to_string()),
+            };
 
-        while self.current < self.chars.len() - 1 && c != symbol {
-            //Check for escape sequence
+            str.push(c);
+            //An escaped quote does not end the string, even in a raw string
             if c == '\\' {
                 self.current += 1;
-                c = self.chars[self.current];
-                match c {
-                    'n' => str.push('\n'),
-                    't' => str.push('\t'),
-                    '\\' => str.push('\\'),
-                    '"' => str.push('"'),
-                    '\'' => str.push('\''),
-                    '0' => str.push('\0'),
-                    _ => panic!("Unrecognised escape sequence \\{}", c),
-                }
-            } else {
+                match self.chars.get(self.current) {
+                    Some(c) => str.push(*c),
+                    None => return self.fail("Unterminated string!".to_string()),
+                }
+            }
+        }
+
+        if triple {
+            str = dedent(&str);
+        }
+        match raw {
+            true => Some(str),
+            false => self.unescape(&str),
+        }
+    }
+
+    fn unescape(&mut self, raw: &str) -> Option<String> {
+        let mut str = String::new();
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
                 str.push(c);
+                continue;
             }
 
-            self.current += 1;
-            c = self.chars[self.current];
+            match chars.next() {
+                Some('n') => str.push('\n'),
+                Some('t') => str.push('\t'),
+                Some('r') => str.push('\r'),
+                Some('\\') => str.push('\\'),
+                Some('"') => str.push('"'),
+                Some('\'') => str.push('\''),
+                Some('0') => str.push('\0'),
+                //\x takes exactly two hex digits, up to 7F
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(b) if hex.len() == 2 && b <= 0x7f => str.push(b as char),
+                        _ => return self.fail(format!("Invalid escape sequence \\x{}!", hex)),
+                    }
+                }
+                //\u{...} takes 1 to 6 hex digits naming a Unicode scalar value
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let hex = rest
+                        .strip_prefix('{')
+                        .and_then(|r| r.split_once('}'))
+                        .map(|(hex, _)| hex);
+                    let c = hex
+                        .filter(|hex| (1..=6).contains(&hex.len()))
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32);
+                    match (hex, c) {
+                        (Some(hex), Some(c)) => {
+                            str.push(c);
+                            chars = rest[hex.len() + 2..].chars();
+                        }
+                        _ => {
+                            let mut shown = String::new();
+                            for c in rest.chars().take_while(|c| !c.is_whitespace()) {
+                                shown.push(c);
+                                if c == '}' {
+                                    break;
+                                }
+                            }
+                            return self.fail(format!("Invalid escape sequence \\u{}!", shown));
+                        }
+                    }
+                }
+                Some(c) => return self.fail(format!("Unrecognised escape sequence \\{}", c)),
+                None => return self.fail("Unterminated string!".to_string()),
+            }
         }
 
-        str
+        Some(str)
     }
 
-    fn seek(&mut self, op: Op, err: &str) {
+    fn seek(&mut self, op: Op, err: &str) -> Result<(), String> {
         let mut old = self.current;
         loop {
             match self.next() {
                 Some(o) => {
                     if o == op {
                         self.current = old;
-                        return;
+                        return Ok(());
                     }
                 }
-                None => panic!("{}", err),
+                None => return Err(err.to_string()),
             }
             old = self.current;
         }
@@ -545,6 +1223,11 @@ impl Lexer {
             }
         }
 
+        //The file ended part way through s
+        if temp < s.len() {
+            return false;
+        }
+
         self.current += temp - 1;
         true
     }
@@ -576,30 +1259,26 @@ impl Lexer {
         }
     }
 
-    fn read_until_space(&mut self, offset: usize) -> String {
+    //Names follow the Unicode identifier rules: an XID_Start character then any XID_Continue characters.
+    //They are compared exactly as written, without normalisation
+    fn read_name(&mut self, offset: usize) -> String {
         self.current += offset;
         let mut s = String::new();
-        loop {
-            if self.current >= self.chars.len() {
-                return s;
-            }
-
-            let c = self.chars[self.current];
-            if c.is_ascii_whitespace() || c == '[' || c == '{' || c == '}' {
-                self.current -= 1;
-                return s;
-            }
-            s.push(c);
+        while self.current < self.chars.len() && self.chars[self.current].is_xid_continue() {
+            s.push(self.chars[self.current]);
             self.current += 1;
         }
+
+        self.current -= 1;
+        s
     }
 
-    fn exit_body(&mut self) {
+    fn exit_body(&mut self) -> Result<(), String> {
         let mut skips = 0;
         loop {
             let next = self.next();
             let op = match next {
-                None => panic!("Missing closing brace!"),
+                None => return Err("Missing closing brace!".to_string()),
                 Some(v) => v,
             };
 
@@ -608,7 +1287,7 @@ impl Lexer {
                     skips += 1;
                 } else if g == Glyph::CloseSquiggle {
                     if skips == 1 {
-                        return;
+                        return Ok(());
                     } else {
                         skips -= 1;
                     }
@@ -617,6 +1296,21 @@ impl Lexer {
         }
     }
 
+    //Convert a position in chars into a 1-based (line, column) pair.
+    //The leading newline added in main() means line 1 starts at index 1
+    fn location(&self, pos: usize) -> (usize, usize) {
+        let mut line = 0;
+        let mut line_start = 0;
+        for (i, c) in self.chars.iter().enumerate().take(pos) {
+            if *c == '\n' {
+                line += 1;
+                line_start = i;
+            }
+        }
+
+        (line.max(1), pos - line_start)
+    }
+
     fn context(&mut self) {
         while self.current < self.chars.len() {
             print!("{}", self.chars[self.current]);
@@ -627,161 +1321,1112 @@ impl Lexer {
     }
 }
 
-fn main() {
-    //accept filename from terminal
-    let args: Vec<String> = env::args().collect();
-    let mut file = std::fs::File::open(&args[1]).unwrap();
-    let mut data = String::new();
-    file.read_to_string(&mut data).unwrap();
-
-    //append newline to start of data - to make lexing easier
-    let data = "\n".to_string() + &data;
+//Triple-quoted strings drop the newline straight after the opening quotes, and the indentation
+//common to every line. A last line of only indentation before the closing quotes counts towards
+//the common indentation, and is then dropped so the string ends with a newline
+fn dedent(text: &str) -> String {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let lines: Vec<&str> = text.split('\n').collect();
+    let blank = |l: &str| l.trim().is_empty();
+    let indent = |l: &str| l.chars().take_while(|c| c.is_whitespace()).count();
+
+    let last = lines.len() - 1;
+    let common = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, l)| !blank(l) || (*i == last && last > 0))
+        .map(|(_, l)| indent(l))
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|l| match blank(l) {
+            true => String::new(),
+            false => l.chars().skip(common).collect(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
 
+//Interpreter state, everything needed to run a program
+struct Interpreter {
     //Main structures
-    let mut lex = Lexer::new(data);
-    let mut stack = OpStack::new();
-    let mut var_store = VarStore::new();
+    lex: Lexer,
+    stack: OpStack,
+    var_store: VarStore,
 
     //Sub structures
-    let mut for_open = false;
-    let mut func_open = (false, 0usize);
-    let mut loop_stack = Vec::<Brace>::new();
+    for_open: bool,
+    func_open: (bool, usize),
+    loop_stack: Vec<Brace>,
+    labels: HashMap<usize, String>, //label of the loop whose first '{' is at each position
+
+    trace: Option<trace::Tracer>,
+    profile: Option<profile::Profiler>,
+    jit: Option<jit::Jit>,
+
+    out: Box<dyn Write>,
+    input: Box<dyn BufRead>,
+    limit: Option<usize>, //fail after this many steps, so that every program terminates
+    thrown: Option<Operand>, //the value being thrown by '!', while the error unwinds
+}
+
+impl Interpreter {
+    fn new(data: String) -> Interpreter {
+        Interpreter {
+            lex: Lexer::new(data),
+            stack: OpStack::new(),
+            var_store: VarStore::new(),
+            for_open: false,
+            func_open: (false, 0usize),
+            loop_stack: Vec::<Brace>::new(),
+            labels: HashMap::new(),
+            trace: None,
+            profile: None,
+            jit: None,
+            out: Box::new(io::stdout()),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            limit: None,
+            thrown: None,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        self.prepare()?;
+        self.execute()
+    }
+
+    //Run only the function, as if it were called at the end of the file
+    fn run_function(&mut self, name: &str) -> Result<(), Error> {
+        self.prepare()?;
+        let id = self.var_store.intern(name);
+        if let Some(call) = self.var_store.calls[id] {
+            self.lex.current = self.lex.chars.len() - 1;
+            self.call(id, call);
+        }
+        self.execute()
+    }
+
+    //Find the functions and intern the names, and in lexical mode check every name
+    //can be found before anything runs
+    fn prepare(&mut self) -> Result<(), Error> {
+        self.lex.register_functions(&mut self.var_store)?;
+        self.lex.resolve(&mut self.var_store);
+        self.var_store.lexical = self.lex.lexical;
+        self.var_store.block_scopes = self.lex.block_scopes;
+
+        if self.lex.lexical {
+            let mut lex = Lexer::new(self.lex.chars.iter().collect());
+            let program = parse::parse(&mut lex);
+            //Names inside blocks the parser skipped cannot be resolved
+            let too_deep = program
+                .diagnostics
+                .into_iter()
+                .filter(|d| d.msg == parse::TOO_DEEP);
+            if let Some(d) = too_deep.chain(check::resolve(&program.body)).next() {
+                return Err(Error {
+                    pos: d.pos,
+                    msg: d.msg,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), Error> {
+        let mut steps = 0usize;
+        while let Some(step) = self.lex.next_step() {
+            let (start, end) = (self.lex.start, self.lex.current);
+            let (kind, result) = match step {
+                Step::Op(op) => (dump::kind(&op), self.step(op)),
+                Step::Named(named, id) => (dump::named_kind(named), self.named(named, id)),
+            };
+            if let Err(msg) = result {
+                //A lexing error is what made the step fail, and cannot be caught
+                if let Some(e) = self.lex.error.take() {
+                    return Err(e);
+                }
+                if let Err(msg) = self.catch(msg) {
+                    return Err(Error { pos: start, msg });
+                }
+            }
 
-    lex.register_functions(&mut var_store);
+            if let Some(trace) = &mut self.trace {
+                trace.step(&self.lex, start, end, &self.stack);
+            }
+            if let Some(profile) = &mut self.profile {
+                let (stack, scopes) = (self.stack.stack.len(), self.var_store.vars.len());
+                profile.step(start, kind, stack, scopes);
+            }
+            //println!("{:?}", self.stack.stack);
+
+            steps += 1;
+            if self.limit.is_some_and(|limit| steps >= limit) {
+                return Err(Error {
+                    pos: start,
+                    msg: "Step limit reached!".to_string(),
+                });
+            }
+        }
+
+        match self.lex.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    //Run the loop starting at the current token as machine code, if the JIT is on and can handle it.
+    //Tracing and profiling need every step, so they turn it off, and native loops have no block scopes
+    fn run_native(&mut self) -> Result<bool, String> {
+        let native = self.trace.is_none() && self.profile.is_none() && !self.var_store.block_scopes;
+        let jit = match &mut self.jit {
+            Some(jit) if native => jit,
+            _ => return Ok(false),
+        };
+
+        match jit.run(self.lex.start, &mut self.var_store)? {
+            Some(close) => {
+                self.lex.current = close;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    //Consume the '{' that must follow, returning its position
+    fn open_block(&mut self, err: &str) -> Result<usize, String> {
+        match self.lex.next() {
+            Some(Op::Glyph(Glyph::OpenSquiggle)) => Ok(self.lex.current),
+            _ => Err(err.to_string()),
+        }
+    }
 
-    'a: loop {
-        let op = match lex.next() {
-            Some(v) => v,
-            None => break 'a,
+    //Unwind to the innermost '!?' and run its handler with the thrown value and the message
+    //on the stack. Without one, the error ends the program
+    fn catch(&mut self, msg: String) -> Result<(), String> {
+        let thrown = self.thrown.take();
+        let i = match self
+            .loop_stack
+            .iter()
+            .rposition(|b| matches!(b, Brace::OpenTry { .. }))
+        {
+            Some(i) => i,
+            None => {
+                return Err(match thrown {
+                    Some(value) => format!("Uncaught error: {}", value),
+                    None => msg,
+                })
+            }
         };
 
+        //Functions called inside the block are left without going back to their callers
+        while self.loop_stack.len() > i + 1 {
+            if let Some(Brace::OpenFunc(_)) = self.close() {
+                if let Some(trace) = &mut self.trace {
+                    trace.scope_pop(&self.lex, self.var_store.vars.len());
+                }
+                if let Some(profile) = &mut self.profile {
+                    profile.ret();
+                }
+                self.var_store.destroy_scope();
+            }
+        }
+        self.for_open = false;
+        self.func_open = (false, 0);
+
+        if let Some(Brace::OpenTry { body, stack }) = self.close() {
+            self.stack.stack = stack;
+            self.skip_block(body)?;
+            self.open_block("Try must be followed by a handler block!")?;
+            self.open(Brace::Open);
+        }
+
+        //A runtime error's value is its message
+        self.stack
+            .push(thrown.unwrap_or_else(|| Operand::String(msg.as_str().into())));
+        self.stack.push(Operand::String(msg.into()));
+        Ok(())
+    }
+
+    //Push a brace, giving its block a scope of its own in block-scopes mode. A function's
+    //scope is made by call() instead
+    fn open(&mut self, brace: Brace) {
+        if self.var_store.block_scopes && !matches!(brace, Brace::OpenFunc(_)) {
+            self.new_block_scope();
+        }
+        self.loop_stack.push(brace);
+    }
+
+    //Pop a brace, destroying its block's scope. A function's is left to leave_function()
+    fn close(&mut self) -> Option<Brace> {
+        let brace = self.loop_stack.pop();
+        if self.var_store.block_scopes && !matches!(brace, Some(Brace::OpenFunc(_)) | None) {
+            self.destroy_block_scope();
+        }
+        brace
+    }
+
+    //Pop the braces above the first `len`, for '$', '^' and '$$'. None of them are functions'
+    fn close_to(&mut self, len: usize) {
+        while self.loop_stack.len() > len {
+            self.close();
+        }
+    }
+
+    //A loop going round again starts its body with a new scope
+    fn next_iteration(&mut self) {
+        if self.var_store.block_scopes {
+            self.destroy_block_scope();
+            self.new_block_scope();
+        }
+    }
+
+    fn new_block_scope(&mut self) {
+        self.var_store.new_block_scope();
+        if let Some(trace) = &mut self.trace {
+            trace.block_push(&self.lex, self.var_store.vars.len());
+        }
+    }
+
+    fn destroy_block_scope(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            trace.block_pop(&self.lex, self.var_store.vars.len());
+        }
+        self.var_store.destroy_scope();
+    }
+
+    //Fails if the name is a constant, naming where it was defined
+    fn writable(&self, id: usize) -> Result<(), String> {
+        match self.var_store.constant(id) {
+            Some(pos) => {
+                let (line, col) = self.lex.location(pos);
+                Err(format!(
+                    "Cannot assign to constant '{}', defined at {}:{}!",
+                    self.var_store.names[id], line, col
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    //Push the variable, or failing that call the function, with the name
+    fn load(&mut self, id: usize) -> Result<(), String> {
+        if self.stack.access(&mut self.var_store, id) {
+            return Ok(());
+        }
+
+        match self.var_store.calls[id] {
+            Some(call) => self.call(id, call),
+            None => {
+                return Err(format!(
+                    "Unknown variable '{}' on level {}!",
+                    self.var_store.names[id],
+                    self.var_store.vars.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, id: usize) -> Result<(), String> {
+        self.writable(id)?;
+        self.stack.assign(&mut self.var_store, id)
+    }
+
+    //Run a token whose name resolve() has interned
+    fn named(&mut self, named: Named, id: usize) -> Result<(), String> {
+        match named {
+            Named::Load => self.load(id),
+            Named::Store => self.store(id),
+            Named::Constant => {
+                self.writable(id)?;
+                let val = self.stack.pop_assigned()?;
+                self.var_store.set_constant(id, val, self.lex.start)
+            }
+            Named::AssignOuter => {
+                self.writable(id)?;
+                let val = self.stack.pop_assigned()?;
+                match self.var_store.set_outer(id, val) {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "Cannot assign to unknown variable '{}'!",
+                        self.var_store.names[id]
+                    )),
+                }
+            }
+            Named::AssignGlobal => {
+                self.writable(id)?;
+                let val = self.stack.pop_assigned()?;
+                self.var_store.set_global(id, val)
+            }
+            Named::Count => self.count(id),
+            Named::Each => self.each(id),
+        }
+    }
+
+    fn count(&mut self, id: usize) -> Result<(), String> {
+        let count = self.stack.pop_int("Counted loop requires an Int!")?;
+        let pos = self.open_block("Loop must be followed by '{'!")?;
+        if count > 0 {
+            self.writable(id)?;
+            self.var_store.set_var(id, Operand::Int(0))?;
+            self.open(Brace::OpenCount {
+                pos,
+                id,
+                index: 0,
+                count,
+            });
+        } else {
+            self.skip_block(pos)?;
+        }
+        Ok(())
+    }
+
+    fn each(&mut self, id: usize) -> Result<(), String> {
+        let chars: Vec<char> = match self.stack.pop()? {
+            Operand::String(s) => s.chars().collect(),
+            _ => return Err("For-each loop requires a String!".to_string()),
+        };
+        let pos = self.open_block("Loop must be followed by '{'!")?;
+        if chars.is_empty() {
+            self.skip_block(pos)?;
+        } else {
+            self.writable(id)?;
+            self.var_store
+                .set_var(id, Operand::String(chars[0].to_string().into()))?;
+            self.open(Brace::OpenEach {
+                pos,
+                id,
+                chars,
+                index: 0,
+            });
+        }
+        Ok(())
+    }
+
+    //Jump into the function whose '{' follows `pos`, coming back to the current position
+    fn call(&mut self, id: usize, (pos, layout): (usize, usize)) {
+        self.func_open = (true, self.lex.current);
+        self.lex.current = pos;
+
+        self.var_store.new_scope(layout);
+        let name = &self.var_store.names[id];
+        if let Some(trace) = &mut self.trace {
+            trace.scope_push(&self.lex, name, self.var_store.vars.len());
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.call(name);
+        }
+    }
+
+    //Go back to the caller, whose brace has already been popped
+    fn leave_function(&mut self, ret: usize) {
+        if let Some(trace) = &mut self.trace {
+            trace.scope_pop(&self.lex, self.var_store.vars.len());
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.ret();
+        }
+        self.lex.current = ret;
+        self.var_store.destroy_scope();
+    }
+
+    //Index in loop_stack of the loop a '$' or '^' applies to: the innermost one, or the
+    //one with the label. With `functions`, an unlabelled '$' can also return from a function
+    fn find_loop(&self, label: &Option<String>, functions: bool) -> Option<usize> {
+        for i in (0..self.loop_stack.len()).rev() {
+            let brace = &self.loop_stack[i];
+            if let Brace::OpenFunc(_) = brace {
+                return if functions && label.is_none() {
+                    Some(i)
+                } else {
+                    None
+                };
+            }
+
+            if let Some(pos) = brace.loop_pos() {
+                if label.is_none() || label.as_ref() == self.labels.get(&pos) {
+                    return Some(i);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn no_loop(label: &Option<String>, err: &str) -> String {
+        match label {
+            Some(l) => format!("No enclosing loop labelled '{}'!", l),
+            None => err.to_string(),
+        }
+    }
+
+    //After a branch that did not run: run the else block, or start on an else-if's condition
+    fn not_taken(&mut self) -> Result<(), String> {
+        match self.lex.peek() {
+            Some(Op::Glyph(Glyph::Else)) => {
+                self.lex.next();
+            }
+            Some(Op::Glyph(Glyph::ElseIf)) => {
+                self.lex.next();
+                self.open_block("Else-if must be followed by '{'!")?;
+                self.open(Brace::OpenElseIf);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    //Pop the value to match and run the first case equal to it, or the default case
+    fn run_match(&mut self) -> Result<(), String> {
+        let value = self.stack.pop()?;
+        let outer = self.open_block("Match must be followed by '{'!")?;
+        loop {
+            let matched = match self.lex.next() {
+                Some(Op::Operand(o)) => o == value,
+                Some(Op::Glyph(Glyph::Else)) => true,
+                Some(Op::Glyph(Glyph::CloseSquiggle)) => return Ok(()),
+                None => return Err("Missing closing brace!".to_string()),
+                _ => return Err("Match cases must be literals!".to_string()),
+            };
+
+            let pos = self.open_block("Match case must be followed by '{'!")?;
+            if matched {
+                self.open(Brace::OpenCase(outer));
+                return Ok(());
+            }
+            self.skip_block(pos)?;
+        }
+    }
+
+    //Skip the rest of the block opened at pos
+    fn skip_block(&mut self, pos: usize) -> Result<(), String> {
+        self.lex.current = pos - 1;
+        self.lex.exit_body()
+    }
+
+    fn step(&mut self, op: Op) -> Result<(), String> {
         match op {
             //Dealing with loops, ensuring that when the latter bracket of a loop is reached, we actually loop
             //Requires a stack to keep track of which brackets are for what, etc
             Op::Glyph(g) => match g {
-                Glyph::Loop => for_open = true,
+                Glyph::Loop => self.for_open = !self.run_native()?,
+                Glyph::Count(name) | Glyph::Each(name) => return Err(unresolved(&name)),
+                Glyph::While => {
+                    let pos = self.open_block("Loop must be followed by '{'!")?;
+                    self.open(Brace::OpenWhileCond(pos));
+                }
                 Glyph::OpenSquiggle => {
-                    if for_open {
-                        loop_stack.push(Brace::OpenFor(lex.current));
-                        for_open = false;
-                    } else if func_open.0 {
-                        loop_stack.push(Brace::OpenFunc(func_open.1));
-                        func_open = (false, 0usize);
+                    if self.for_open {
+                        self.open(Brace::OpenFor(self.lex.current));
+                        self.for_open = false;
+                    } else if self.func_open.0 {
+                        self.open(Brace::OpenFunc(self.func_open.1));
+                        self.func_open = (false, 0usize);
                     } else {
-                        loop_stack.push(Brace::Open);
+                        self.open(Brace::Open);
                     }
                 }
                 Glyph::CloseSquiggle => {
-                    if loop_stack.is_empty() {
-                        panic!("Unmatched braces!");
+                    if self.loop_stack.is_empty() {
+                        return Err("Unmatched braces!".to_string());
                     }
 
-                    match loop_stack[loop_stack.len() - 1] {
+                    let last = self.loop_stack.len() - 1;
+                    match &mut self.loop_stack[last] {
                         Brace::Open => {
-                            loop_stack.pop();
+                            self.close();
                         }
                         //If we're closing a loop, loop
                         Brace::OpenFor(pos) => {
-                            lex.current = pos;
+                            self.lex.current = *pos;
+                            self.next_iteration();
+                        }
+                        Brace::OpenCount {
+                            pos,
+                            id,
+                            index,
+                            count,
+                        } => {
+                            *index += 1;
+                            if index < count {
+                                let (id, index) = (*id, *index);
+                                self.lex.current = *pos;
+                                self.next_iteration();
+                                self.var_store.set_var(id, Operand::Int(index))?;
+                            } else {
+                                self.close();
+                            }
+                        }
+                        Brace::OpenEach {
+                            pos,
+                            id,
+                            chars,
+                            index,
+                        } => {
+                            *index += 1;
+                            if *index < chars.len() {
+                                let c = Operand::String(chars[*index].to_string().into());
+                                let id = *id;
+                                self.lex.current = *pos;
+                                self.next_iteration();
+                                self.var_store.set_var(id, c)?;
+                            } else {
+                                self.close();
+                            }
+                        }
+                        //The end of the condition decides whether the body runs
+                        Brace::OpenWhileCond(cond) => {
+                            let cond = *cond;
+                            self.close();
+                            let go = match self.stack.pop()? {
+                                Operand::Bool(b) => b,
+                                _ => {
+                                    return Err(
+                                        "While loop condition must leave a Bool!".to_string()
+                                    )
+                                }
+                            };
+
+                            let body =
+                                self.open_block("While loop condition must be followed by '{'!")?;
+                            if go {
+                                self.open(Brace::OpenWhile { cond, body });
+                            } else {
+                                self.skip_block(body)?;
+                            }
+                        }
+                        Brace::OpenElseIf => {
+                            self.close();
+                            let go = self.stack.cond()?;
+                            let body =
+                                self.open_block("Else-if condition must be followed by '{'!")?;
+                            if go {
+                                self.open(Brace::Open);
+                            } else {
+                                self.skip_block(body)?;
+                                self.not_taken()?;
+                            }
+                        }
+                        //Nothing went wrong, so the handler is skipped
+                        Brace::OpenTry { .. } => {
+                            self.close();
+                            let handler =
+                                self.open_block("Try must be followed by a handler block!")?;
+                            self.skip_block(handler)?;
+                        }
+                        //The rest of the cases are skipped
+                        Brace::OpenCase(outer) => {
+                            let outer = *outer;
+                            self.close();
+                            self.skip_block(outer)?;
+                        }
+                        //The end of the body goes back to the condition
+                        Brace::OpenWhile { cond, .. } => {
+                            let cond = *cond;
+                            self.loop_stack[last] = Brace::OpenWhileCond(cond);
+                            self.lex.current = cond;
+                            self.next_iteration();
                         }
                         Brace::OpenFunc(ret) => {
-                            lex.current = ret;
-                            var_store.destroy_scope();
-                            loop_stack.pop();
+                            let ret = *ret;
+                            self.close();
+                            self.leave_function(ret);
                         }
-                        _ => (),
                     };
                 }
-                Glyph::Break => {
-                    //Can probably be improved, instead of jumping to the top of the scope and exiting, instead use the already tracked brackets to get out
-                    let mut found = false;
-
-                    for i in (0..loop_stack.len()).rev() {
-                        let brace = &mut loop_stack[i];
-                        match brace {
-                            Brace::OpenFor(pos) => {
-                                //Current should be pos-1 because pos is the bracket,
-                                //so we must go before that for exit_body() to work correctly
-                                lex.current = *pos - 1;
-                                lex.exit_body();
-
-                                found = true;
-                                break;
-                            }
-                            Brace::OpenFunc(ret) => {
-                                lex.current = *ret;
-                                found = true;
-                                break;
-                            }
-                            _ => (),
-                        };
+                Glyph::Label(name) => {
+                    //Remember which '{' the label belongs to, then run the loop as usual
+                    let old = self.lex.current;
+                    match self.lex.next() {
+                        Some(Op::Glyph(Glyph::Loop | Glyph::Count(_) | Glyph::Each(_))) => (),
+                        Some(Op::Glyph(Glyph::While)) => (),
+                        _ => return Err("Label must be followed by a loop!".to_string()),
+                    }
+                    let pos = self.open_block("Loop must be followed by '{'!")?;
+                    self.labels.insert(pos, name);
+                    self.lex.current = old;
+                }
+                Glyph::Break(label) => {
+                    let i = match self.find_loop(&label, true) {
+                        Some(i) => i,
+                        None => {
+                            return Err(Interpreter::no_loop(&label, "Can only break from a loop!"))
+                        }
+                    };
 
-                        loop_stack.pop();
+                    //Everything opened inside the loop is left along with it
+                    self.close_to(i + 1);
+                    match self.close() {
+                        Some(Brace::OpenFunc(ret)) => self.leave_function(ret),
+                        //Breaking out of a condition skips the body as well
+                        Some(Brace::OpenWhileCond(pos)) => {
+                            self.skip_block(pos)?;
+                            let body =
+                                self.open_block("While loop condition must be followed by '{'!")?;
+                            self.skip_block(body)?;
+                        }
+                        Some(Brace::OpenWhile { body, .. }) => self.skip_block(body)?,
+                        Some(brace) => self.skip_block(brace.loop_pos().unwrap_or(0))?,
+                        None => (),
                     }
+                }
+                Glyph::Return => {
+                    let i = match self
+                        .loop_stack
+                        .iter()
+                        .rposition(|b| matches!(b, Brace::OpenFunc(_)))
+                    {
+                        Some(i) => i,
+                        None => return Err("Can only return from a function!".to_string()),
+                    };
 
-                    if !found {
-                        panic!("Can only break from a loop!");
-                    } else {
-                        loop_stack.pop();
+                    //Any loops and conditionals still open in the function are left with it
+                    self.close_to(i + 1);
+                    if let Some(Brace::OpenFunc(ret)) = self.close() {
+                        self.leave_function(ret);
+                    }
+                }
+                Glyph::Continue(label) => {
+                    let i = match self.find_loop(&label, false) {
+                        Some(i) => i,
+                        None => {
+                            return Err(Interpreter::no_loop(
+                                &label,
+                                "Can only continue inside a loop!",
+                            ))
+                        }
+                    };
+
+                    self.close_to(i + 1);
+                    match &self.loop_stack[i] {
+                        //Go back to the start of the condition
+                        Brace::OpenWhileCond(cond) => {
+                            self.lex.current = *cond;
+                            self.next_iteration();
+                        }
+                        //Otherwise step back onto the loop's closing brace, as if the body had ended
+                        Brace::OpenWhile { body, .. } => {
+                            self.skip_block(*body)?;
+                            self.lex.current -= 1;
+                        }
+                        brace => {
+                            self.skip_block(brace.loop_pos().unwrap_or(0))?;
+                            self.lex.current -= 1;
+                        }
                     }
                 }
                 //Skip over else's in normal code
                 Glyph::Else => {
-                    lex.seek(Op::Glyph(Glyph::OpenSquiggle), "Missing braces after else!");
-                    lex.exit_body();
+                    self.lex
+                        .seek(Op::Glyph(Glyph::OpenSquiggle), "Missing braces after else!")?;
+                    self.lex.exit_body()?;
+                }
+                //Likewise an else-if's condition and block, once an earlier branch has run
+                Glyph::ElseIf => {
+                    for _ in 0..2 {
+                        self.lex.seek(
+                            Op::Glyph(Glyph::OpenSquiggle),
+                            "Else-if must be followed by '{'!",
+                        )?;
+                        self.lex.exit_body()?;
+                    }
+                }
+                Glyph::Match => self.run_match()?,
+                Glyph::Try => {
+                    let body = self.open_block("Try must be followed by '{'!")?;
+                    self.open(Brace::OpenTry {
+                        body,
+                        stack: self.stack.stack.clone(),
+                    });
                 }
                 //SKip over define's in normal code
                 Glyph::Define => {
-                    lex.seek(
+                    self.lex.seek(
                         Op::Glyph(Glyph::OpenSquiggle),
                         "Missing braces after define!",
-                    );
-                    lex.exit_body();
+                    )?;
+                    self.lex.exit_body()?;
                 }
                 _ => (),
             },
-            Op::Operand(o) => stack.push(o),
+            Op::Operand(o) => self.stack.push(o),
             Op::Operator(o) => match o {
-                Operator::Add => stack.add(),
-                Operator::Sub => stack.sub(),
-                Operator::Print => stack.print(),
-                Operator::Mul => stack.mul(),
-                Operator::Div => stack.div(),
-                Operator::Equal => stack.equal(),
-                Operator::NotEqual => stack.not_equal(),
-                Operator::LessThan => stack.less_than(),
-                Operator::GreaterThan => stack.greater_than(),
-                Operator::Cond => stack.cond(&mut lex),
-                Operator::Pop => stack.silent_pop(),
-                Operator::Clear => stack.clear(),
-                Operator::Access(s) => {
-                    let outcome = stack.access(&mut var_store, &s);
-
-                    if !outcome {
-                        match var_store.funcs.get(&s) {
-                            Some(pos) => {
-                                //lex.read_until_space(0);
-
-                                func_open = (true, lex.current);
-                                lex.current = *pos;
-
-                                var_store.new_scope();
-                            }
-                            None => panic!(
-                                "Unknown variable '{}' on level {}!",
-                                s,
-                                var_store.vars.len()
-                            ),
-                        }
+                Operator::Add => self.stack.add()?,
+                Operator::Sub => self.stack.sub()?,
+                Operator::Print => self.stack.print(&mut self.out)?,
+                Operator::Mul => self.stack.mul()?,
+                Operator::Div => self.stack.div()?,
+                Operator::Equal => self.stack.equal()?,
+                Operator::NotEqual => self.stack.not_equal()?,
+                Operator::LessThan => self.stack.less_than()?,
+                Operator::GreaterThan => self.stack.greater_than()?,
+                Operator::Cond => {
+                    if !self.stack.cond()? {
+                        self.lex.exit_body()?;
+                        self.not_taken()?;
                     }
                 }
-                Operator::Assign(s) => stack.assign(&mut var_store, s),
-                Operator::Index => stack.get_index(),
-                Operator::Input => stack.read_input(),
-                _ => panic!("WIP"),
+                Operator::Pop => self.stack.silent_pop()?,
+                Operator::Clear => self.stack.clear(),
+                Operator::Access(s)
+                | Operator::Assign(s)
+                | Operator::AssignOuter(s)
+                | Operator::AssignGlobal(s)
+                | Operator::Constant(s) => return Err(unresolved(&s)),
+                Operator::Index => self.stack.get_index()?,
+                Operator::Input => self.stack.read_input(&mut self.input)?,
+                Operator::Assert => self.stack.assert()?,
+                Operator::AssertEq => self.stack.assert_eq()?,
+                Operator::Throw => {
+                    let value = self.stack.pop()?;
+                    let msg = value.to_string();
+                    self.thrown = Some(value);
+                    return Err(msg);
+                }
+                _ => return Err("WIP".to_string()),
+            },
+        }
+
+        Ok(())
+    }
+}
+
+fn read_source(path: &str) -> String {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    //append newline to start of data - to make lexing easier.
+    //Invalid UTF-8 is replaced rather than rejected, so any file can be lexed
+    "\n".to_string() + &String::from_utf8_lossy(&data)
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: aqua [--dump-tokens | --dump-ast] [--json] <file>");
+    eprintln!("       aqua [--trace] [--trace-file <path>] [--trace-fn <name>] [--trace-limit <n>] <file>");
+    eprintln!("       aqua [--profile] [--profile-folded <path>] <file>");
+    eprintln!("       aqua [--jit] [--lexical] [--block-scopes] <file>");
+    eprintln!("       aqua check [--signatures] [--lexical] <file>");
+    eprintln!("       aqua test <file>");
+    eprintln!("       aqua fmt [--check] <file>...");
+    eprintln!("       aqua lsp");
+    eprintln!("       aqua compile [--emit c] [-o <path>] [--lexical] [--block-scopes] <file>");
+    process::exit(2);
+}
+
+//Report every static problem in the file, without running it
+fn check_file(args: &[String]) {
+    let mut signatures = false;
+    let mut lexical = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--signatures" => signatures = true,
+            "--lexical" => lexical = true,
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let mut lex = Lexer::new(read_source(path));
+    lex.lexical |= lexical;
+    let analysis = check::check(&mut lex);
+
+    if signatures {
+        for (pos, name, sig) in &analysis.signatures {
+            let (line, col) = lex.location(*pos);
+            println!("{}:{}:{}: #{} {}", path, line, col, name, sig);
+        }
+    }
+
+    for d in &analysis.diagnostics {
+        let (line, col) = lex.location(d.pos);
+        println!("{}:{}:{}: {}", path, line, col, d.msg);
+    }
+
+    if !analysis.diagnostics.is_empty() {
+        process::exit(1);
+    }
+}
+
+//Run each function named test_*, in the order they are defined, with nothing but its own
+//stack and variables. Top-level code is not run
+fn test_file(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => usage(),
+    };
+    let source = read_source(path);
+
+    let mut lex = Lexer::new(source.clone());
+    let mut var_store = VarStore::new();
+    if let Err(e) = lex.register_functions(&mut var_store) {
+        let (line, col) = lex.location(e.pos);
+        eprintln!("{}:{}:{}: {}", path, line, col, e.msg);
+        process::exit(1);
+    }
+    let mut tests: Vec<(usize, String)> = var_store
+        .funcs
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("test_"))
+        .map(|(name, pos)| (pos, name))
+        .collect();
+    tests.sort();
+
+    let mut failed = 0;
+    for (_, name) in &tests {
+        let mut interpreter = Interpreter::new(source.clone());
+        match interpreter.run_function(name) {
+            Ok(()) => println!("PASS {}", name),
+            Err(e) => {
+                failed += 1;
+                let (line, col) = interpreter.lex.location(e.pos);
+                println!("FAIL {}: {}:{}:{}: {}", name, path, line, col, e.msg);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+//Rewrite files in canonical style, or with --check only report the ones that differ
+fn format_files(args: &[String]) {
+    let check = args.iter().any(|a| a == "--check");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut failed = false;
+    for path in paths {
+        let data = read_source(path);
+        let mut lex = Lexer::new(data.clone());
+        let out = match format::format(&mut lex) {
+            Ok(out) => out,
+            Err(diagnostics) => {
+                for d in &diagnostics {
+                    let (line, col) = lex.location(d.pos);
+                    eprintln!("{}:{}:{}: {}", path, line, col, d.msg);
+                }
+                failed = true;
+                continue;
+            }
+        };
+
+        if out == data[1..] {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            failed = true;
+        } else if let Err(e) = std::fs::write(path, out) {
+            eprintln!("Cannot write {}: {}", path, e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+//Translate a program into another language, C being the only target so far
+fn compile_file(args: &[String]) {
+    let mut output = None;
+    let mut lexical = false;
+    let mut block_scopes = false;
+    let mut path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--emit" => match iter.next().map(String::as_str) {
+                Some("c") => (),
+                _ => usage(),
             },
+            "-o" => output = Some(iter.next().unwrap_or_else(|| usage())),
+            "--lexical" => lexical = true,
+            "--block-scopes" => block_scopes = true,
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let mut lex = Lexer::new(read_source(path));
+    lex.lexical |= lexical;
+    lex.block_scopes |= block_scopes;
+    let mut program = parse::parse(&mut lex);
+    //Names are resolved before anything runs in lexical mode, so the same goes for compiling
+    if lex.lexical {
+        program
+            .diagnostics
+            .append(&mut check::resolve(&program.body));
+    }
+    if !program.diagnostics.is_empty() {
+        for d in &program.diagnostics {
+            let (line, col) = lex.location(d.pos);
+            eprintln!("{}:{}:{}: {}", path, line, col, d.msg);
         }
+        process::exit(1);
+    }
 
-        //println!("{:?}", stack.stack);
+    let c = compile::emit_c(&program, &lex);
+    match output {
+        Some(out) => {
+            if let Err(e) = std::fs::write(out, c) {
+                eprintln!("Cannot write {}: {}", out, e);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", c),
+    }
+}
+
+fn main() {
+    //accept filename from terminal
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("check") => {
+            check_file(&args[2..]);
+            return;
+        }
+        Some("test") => {
+            test_file(&args[2..]);
+            return;
+        }
+        Some("fmt") => {
+            format_files(&args[2..]);
+            return;
+        }
+        Some("compile") => {
+            compile_file(&args[2..]);
+            return;
+        }
+        Some("lsp") => {
+            //The only way writing fails is the client going away, so there is no one to tell
+            if lsp::run().is_err() {
+                process::exit(1);
+            }
+            return;
+        }
+        _ => (),
+    };
+
+    let mut dump_tokens = false;
+    let mut dump_ast = false;
+    let mut json = false;
+    let mut trace: Option<trace::Tracer> = None;
+    let mut profile: Option<profile::Profiler> = None;
+    let mut jit = false;
+    let mut lexical = false;
+    let mut block_scopes = false;
+    let mut path = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().map(String::as_str).unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--dump-tokens" => dump_tokens = true,
+            "--dump-ast" => dump_ast = true,
+            "--json" => json = true,
+            "--trace" => {
+                trace.get_or_insert_with(trace::Tracer::new);
+            }
+            "--trace-file" => {
+                let path = value();
+                let file = std::fs::File::create(path).unwrap_or_else(|e| {
+                    eprintln!("Cannot create {}: {}", path, e);
+                    process::exit(1);
+                });
+                trace.get_or_insert_with(trace::Tracer::new).out =
+                    Box::new(io::BufWriter::new(file));
+            }
+            "--trace-fn" => {
+                trace.get_or_insert_with(trace::Tracer::new).function = Some(value().to_string())
+            }
+            "--trace-limit" => {
+                let limit = value().parse().unwrap_or_else(|_| usage());
+                trace.get_or_insert_with(trace::Tracer::new).limit = Some(limit);
+            }
+            "--profile" => {
+                profile.get_or_insert_with(profile::Profiler::new);
+            }
+            "--profile-folded" => {
+                profile.get_or_insert_with(profile::Profiler::new).folded =
+                    Some(value().to_string());
+            }
+            "--jit" => jit = true,
+            "--lexical" => lexical = true,
+            "--block-scopes" => block_scopes = true,
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    if dump_tokens || dump_ast {
+        let mut lex = Lexer::new(read_source(path));
+        if dump_tokens {
+            print!("{}", dump::tokens(&mut lex, json));
+            if let (Some(e), false) = (&lex.error, json) {
+                let (line, col) = lex.location(e.pos);
+                eprintln!("{}:{}:{}: {}", path, line, col, e.msg);
+            }
+        }
+        if dump_ast {
+            let (out, diagnostics) = dump::ast(&mut lex, json);
+            print!("{}", out);
+            if !json {
+                for d in &diagnostics {
+                    let (line, col) = lex.location(d.pos);
+                    eprintln!("{}:{}:{}: {}", path, line, col, d.msg);
+                }
+            }
+        }
+        return;
+    }
+
+    let mut interpreter = Interpreter::new(read_source(path));
+    interpreter.lex.lexical |= lexical;
+    interpreter.lex.block_scopes |= block_scopes;
+    interpreter.trace = trace;
+    interpreter.profile = profile;
+    if jit {
+        if jit::supported() {
+            interpreter.jit = Some(jit::Jit::new(&mut interpreter.lex));
+        } else {
+            eprintln!("--jit is only supported on x86-64 Linux, interpreting instead");
+        }
+    }
+    let result = interpreter.run();
+    //The trace and profile are most wanted when the program fails, so they are finished
+    //either way, before exiting loses what is buffered
+    let mut reports = Vec::<String>::new();
+    if let Some(Err(msg)) = interpreter.trace.as_mut().map(trace::Tracer::finish) {
+        reports.push(msg);
+    }
+    if let Some(Err(msg)) = interpreter
+        .profile
+        .as_mut()
+        .map(|p| p.finish(&interpreter.lex))
+    {
+        reports.push(msg);
+    }
+    for msg in &reports {
+        eprintln!("{}", msg);
+    }
+    if let Err(e) = &result {
+        let (line, col) = interpreter.lex.location(e.pos);
+        eprintln!("{}:{}:{}: {}", path, line, col, e.msg);
+    }
+    if result.is_err() || !reports.is_empty() {
+        process::exit(1);
     }
 }
@@ -0,0 +1,426 @@
+//Parser - builds the block structure of a whole program without running it.
+//Used by the static tooling; the interpreter itself still works directly on the Lexer
+use crate::{keyword_error, Glyph, Lexer, Op, Operand, Operator};
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+//Blocks nested deeper than this are skipped rather than parsed, so that the parser and every
+//tool that walks its tree stay well inside the stack
+pub const MAX_DEPTH: usize = 100;
+pub const TOO_DEEP: &str = "Blocks are nested too deeply!";
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub pos: usize,
+    pub msg: String,
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub open: usize,
+    pub close: Option<usize>,
+    pub body: Vec<Node>,
+}
+
+//A case of a match, `value` is None for the default case
+#[derive(Debug)]
+pub struct Case {
+    pub pos: usize,
+    pub value: Option<Operand>,
+    pub block: Block,
+}
+
+#[derive(Debug)]
+pub enum NodeKind {
+    Operand(Operand),
+    Operator(Operator),
+    Block(Block),
+    Loop(Block),
+    Count(String, Block),
+    Each(String, Block),
+    While(Block, Block),
+    Cond(Block, Vec<(Block, Block)>, Option<Block>), //then, else-ifs as (condition, block), else
+    Match(Vec<Case>),
+    Try(Block, Block), //guarded block, handler
+    Define(String, Block),
+    Label(String), //always directly followed by the loop it names
+    Break(Option<String>),
+    Continue(Option<String>),
+    Return,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub pos: usize,
+    pub kind: NodeKind,
+}
+
+pub struct Program {
+    pub body: Vec<Node>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<(usize, Op)>>,
+    diagnostics: Vec<Diagnostic>,
+    depth: usize, //blocks open around the current token
+}
+
+pub struct Token {
+    pub op: Op,
+    pub start: usize,
+    pub end: usize, //position of the last character of the token
+}
+
+//The keyword a token was read from, if it was one
+fn keyword(op: &Op) -> Option<&'static str> {
+    match op {
+        Op::Operand(Operand::Bool(true)) => Some("true"),
+        Op::Operand(Operand::Bool(false)) => Some("false"),
+        Op::Operator(Operator::Assert | Operator::AssertEq) => Some("assert"),
+        _ => None,
+    }
+}
+
+//Lex the whole file, keeping the position of every token
+pub fn tokens(lex: &mut Lexer) -> Vec<Token> {
+    let mut tokens = Vec::<Token>::new();
+    lex.current = 0;
+    lex.error = None;
+    while let Some(op) = lex.next() {
+        tokens.push(Token {
+            op,
+            start: lex.start,
+            end: lex.current.min(lex.chars.len() - 1),
+        });
+    }
+    lex.current = 0;
+    tokens
+}
+
+pub fn parse(lex: &mut Lexer) -> Program {
+    let tokens: Vec<(usize, Op)> = tokens(lex).into_iter().map(|t| (t.start, t.op)).collect();
+
+    let mut parser = Parser {
+        tokens: tokens.into_iter().peekable(),
+        diagnostics: Vec::<Diagnostic>::new(),
+        depth: 0,
+    };
+    //Everything after a lexing error is lost, so it is reported along with the parse errors
+    if let Some(e) = &lex.error {
+        parser.error(e.pos, &e.msg);
+    }
+
+    let (body, _) = parser.parse_body(None);
+    Program {
+        body,
+        diagnostics: parser.diagnostics,
+    }
+}
+
+impl Parser {
+    fn error(&mut self, pos: usize, msg: &str) {
+        self.diagnostics.push(Diagnostic {
+            pos,
+            msg: msg.to_string(),
+        });
+    }
+
+    fn next(&mut self) -> Option<(usize, Op)> {
+        self.tokens.next()
+    }
+
+    fn peek_is(&mut self, glyph: Glyph) -> bool {
+        match self.tokens.peek() {
+            Some((_, Op::Glyph(g))) => *g == glyph,
+            _ => false,
+        }
+    }
+
+    //Parse until the closing brace of the block opened at `open`, or until the end of
+    //the file at the top level. Returns the body and the position of the closing brace
+    fn parse_body(&mut self, open: Option<usize>) -> (Vec<Node>, Option<usize>) {
+        let mut body = Vec::<Node>::new();
+        loop {
+            let (pos, op) = match self.next() {
+                Some(t) => t,
+                None => {
+                    if let Some(open) = open {
+                        self.error(open, "Missing closing brace!");
+                    }
+                    return (body, None);
+                }
+            };
+
+            let kind = match op {
+                Op::Operand(o) => NodeKind::Operand(o),
+                Op::Operator(Operator::Cond) => {
+                    let block = match self.expect_block(pos, "Conditional must be followed by '{'!")
+                    {
+                        Some(b) => b,
+                        None => continue,
+                    };
+
+                    let mut else_ifs = Vec::<(Block, Block)>::new();
+                    while self.peek_is(Glyph::ElseIf) {
+                        let (else_pos, _) = self.next().unwrap();
+                        let cond =
+                            match self.expect_block(else_pos, "Else-if must be followed by '{'!") {
+                                Some(b) => b,
+                                None => break,
+                            };
+                        let err = "Else-if condition must be followed by '{'!";
+                        match self.expect_block(else_pos, err) {
+                            Some(b) => else_ifs.push((cond, b)),
+                            None => break,
+                        }
+                    }
+
+                    let mut else_block = None;
+                    if self.peek_is(Glyph::Else) {
+                        let (else_pos, _) = self.next().unwrap();
+                        else_block = self.expect_block(else_pos, "Missing braces after else!");
+                    }
+                    NodeKind::Cond(block, else_ifs, else_block)
+                }
+                Op::Operator(o) => NodeKind::Operator(o),
+                Op::Glyph(g) => match g {
+                    Glyph::OpenSquiggle => NodeKind::Block(self.parse_block(pos)),
+                    Glyph::CloseSquiggle => {
+                        if open.is_some() {
+                            return (body, Some(pos));
+                        }
+                        self.error(pos, "Unmatched braces!");
+                        continue;
+                    }
+                    Glyph::Loop => match self.expect_block(pos, "Loop must be followed by '{'!") {
+                        Some(b) => NodeKind::Loop(b),
+                        None => continue,
+                    },
+                    Glyph::Count(name) => {
+                        match self.expect_block(pos, "Loop must be followed by '{'!") {
+                            Some(b) => NodeKind::Count(name, b),
+                            None => continue,
+                        }
+                    }
+                    Glyph::Each(name) => {
+                        match self.expect_block(pos, "Loop must be followed by '{'!") {
+                            Some(b) => NodeKind::Each(name, b),
+                            None => continue,
+                        }
+                    }
+                    Glyph::While => {
+                        let cond = match self.expect_block(pos, "Loop must be followed by '{'!") {
+                            Some(b) => b,
+                            None => continue,
+                        };
+                        let err = "While loop condition must be followed by '{'!";
+                        match self.expect_block(pos, err) {
+                            Some(b) => NodeKind::While(cond, b),
+                            None => continue,
+                        }
+                    }
+                    Glyph::Label(name) => {
+                        let looped = matches!(
+                            self.tokens.peek(),
+                            Some((
+                                _,
+                                Op::Glyph(
+                                    Glyph::Loop | Glyph::Count(_) | Glyph::Each(_) | Glyph::While
+                                )
+                            ))
+                        );
+                        if !looped {
+                            self.error(pos, "Label must be followed by a loop!");
+                            continue;
+                        }
+                        NodeKind::Label(name)
+                    }
+                    Glyph::Break(label) => NodeKind::Break(label),
+                    Glyph::Continue(label) => NodeKind::Continue(label),
+                    Glyph::Return => NodeKind::Return,
+                    Glyph::Else => {
+                        self.error(pos, "Else must follow the block of a conditional!");
+                        //Parse the block anyway so the rest of the file stays in sync
+                        match self.expect_block(pos, "Missing braces after else!") {
+                            Some(b) => NodeKind::Block(b),
+                            None => continue,
+                        }
+                    }
+                    Glyph::ElseIf => {
+                        self.error(pos, "Else-if must follow the block of a conditional!");
+                        //Parse both blocks anyway so the rest of the file stays in sync
+                        for _ in 0..2 {
+                            if let Some(b) =
+                                self.expect_block(pos, "Else-if must be followed by '{'!")
+                            {
+                                body.push(Node {
+                                    pos,
+                                    kind: NodeKind::Block(b),
+                                });
+                            }
+                        }
+                        continue;
+                    }
+                    Glyph::Try => {
+                        let block = match self.expect_block(pos, "Try must be followed by '{'!") {
+                            Some(b) => b,
+                            None => continue,
+                        };
+                        let err = "Try must be followed by a handler block!";
+                        match self.expect_block(pos, err) {
+                            Some(h) => NodeKind::Try(block, h),
+                            None => continue,
+                        }
+                    }
+                    Glyph::Match => match self.parse_match(pos) {
+                        Some(cases) => NodeKind::Match(cases),
+                        None => continue,
+                    },
+                    Glyph::Define => {
+                        let name = match self
+                            .tokens
+                            .next_if(|(_, op)| matches!(op, Op::Operator(Operator::Access(_))))
+                        {
+                            Some((_, Op::Operator(Operator::Access(s)))) => s,
+                            _ => {
+                                //The keywords lex as themselves rather than as names
+                                match self.tokens.peek().and_then(|(_, op)| keyword(op)) {
+                                    Some(word) => {
+                                        self.tokens.next();
+                                        self.error(pos, &keyword_error(word));
+                                    }
+                                    None => {
+                                        self.error(pos, "Function definition is missing a name!")
+                                    }
+                                }
+                                String::new()
+                            }
+                        };
+
+                        match self.expect_block(pos, "Function definition must start with '{'!") {
+                            Some(b) => NodeKind::Define(name, b),
+                            None => continue,
+                        }
+                    }
+                },
+            };
+
+            body.push(Node { pos, kind });
+        }
+    }
+
+    fn parse_block(&mut self, open: usize) -> Block {
+        if self.depth >= MAX_DEPTH {
+            self.error(open, TOO_DEEP);
+            let close = self.skip_block();
+            return Block {
+                open,
+                close,
+                body: Vec::new(),
+            };
+        }
+
+        self.depth += 1;
+        let (body, close) = self.parse_body(Some(open));
+        self.depth -= 1;
+        Block { open, close, body }
+    }
+
+    //Skip to the brace closing the block whose '{' was just read, returning its position
+    fn skip_block(&mut self) -> Option<usize> {
+        let mut depth = 1;
+        for (pos, op) in self.tokens.by_ref() {
+            match op {
+                Op::Glyph(Glyph::OpenSquiggle) => depth += 1,
+                Op::Glyph(Glyph::CloseSquiggle) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(pos);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        None
+    }
+
+    //The cases between the braces of a match
+    fn parse_match(&mut self, pos: usize) -> Option<Vec<Case>> {
+        if !self.peek_is(Glyph::OpenSquiggle) {
+            self.error(pos, "Match must be followed by '{'!");
+            return None;
+        }
+        let (open, _) = self.next().unwrap();
+
+        let mut cases = Vec::<Case>::new();
+        loop {
+            let (case_pos, value) = match self.next() {
+                Some((p, Op::Operand(o))) => (p, Some(o)),
+                Some((p, Op::Glyph(Glyph::Else))) => (p, None),
+                Some((_, Op::Glyph(Glyph::CloseSquiggle))) => return Some(cases),
+                Some((p, _)) => {
+                    self.error(p, "Match cases must be literals!");
+                    //Skip the case's block too, so the rest of the match stays in sync
+                    if self.peek_is(Glyph::OpenSquiggle) {
+                        let (open, _) = self.next().unwrap();
+                        self.parse_block(open);
+                    }
+                    continue;
+                }
+                None => {
+                    self.error(open, "Missing closing brace!");
+                    return Some(cases);
+                }
+            };
+
+            if cases.last().is_some_and(|c| c.value.is_none()) {
+                self.error(case_pos, "The default case must come last!");
+            }
+            if let Some(block) = self.expect_block(case_pos, "Match case must be followed by '{'!")
+            {
+                cases.push(Case {
+                    pos: case_pos,
+                    value,
+                    block,
+                });
+            }
+        }
+    }
+
+    fn expect_block(&mut self, pos: usize, err: &str) -> Option<Block> {
+        if !self.peek_is(Glyph::OpenSquiggle) {
+            self.error(pos, err);
+            return None;
+        }
+
+        let (open, _) = self.next().unwrap();
+        Some(self.parse_block(open))
+    }
+}
+
+impl NodeKind {
+    //The blocks nested directly inside this node, in source order
+    pub fn blocks(&self) -> Vec<&Block> {
+        match self {
+            NodeKind::Block(b)
+            | NodeKind::Loop(b)
+            | NodeKind::Count(_, b)
+            | NodeKind::Each(_, b)
+            | NodeKind::Define(_, b) => vec![b],
+            NodeKind::While(c, b) | NodeKind::Try(c, b) => vec![c, b],
+            NodeKind::Cond(b, else_ifs, e) => {
+                let mut blocks = vec![b];
+                for (c, b) in else_ifs {
+                    blocks.push(c);
+                    blocks.push(b);
+                }
+                blocks.extend(e);
+                blocks
+            }
+            NodeKind::Match(cases) => cases.iter().map(|c| &c.block).collect(),
+            _ => vec![],
+        }
+    }
+}
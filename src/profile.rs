@@ -0,0 +1,165 @@
+//Profiler - counts what the interpreter runs and times each function call
+use crate::Lexer;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct FnStats {
+    calls: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+struct Frame {
+    name: String,
+    start: Instant,
+    children: Duration, //time spent in functions called from this one
+}
+
+pub struct Profiler {
+    pub folded: Option<String>, //path to write folded stacks to, for flamegraph tools
+    ops: HashMap<&'static str, u64>,
+    hits: HashMap<usize, u64>, //steps run at each position, turned into lines at the end
+    functions: HashMap<String, FnStats>,
+    frames: Vec<Frame>,
+    stacks: HashMap<String, Duration>, //exclusive time for each distinct call stack
+    peak_stack: usize,
+    peak_scopes: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            folded: None,
+            ops: HashMap::new(),
+            hits: HashMap::new(),
+            functions: HashMap::new(),
+            frames: vec![Frame {
+                name: "main".to_string(),
+                start: Instant::now(),
+                children: Duration::ZERO,
+            }],
+            stacks: HashMap::new(),
+            peak_stack: 0,
+            peak_scopes: 0,
+        }
+    }
+
+    pub fn step(&mut self, start: usize, kind: &'static str, stack: usize, scopes: usize) {
+        *self.ops.entry(kind).or_insert(0) += 1;
+        *self.hits.entry(start).or_insert(0) += 1;
+        self.peak_stack = self.peak_stack.max(stack);
+        self.peak_scopes = self.peak_scopes.max(scopes);
+    }
+
+    pub fn call(&mut self, name: &str) {
+        self.functions.entry(name.to_string()).or_default().calls += 1;
+        self.frames.push(Frame {
+            name: name.to_string(),
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    pub fn ret(&mut self) {
+        //The bottom frame is the top level of the program, it only ends in finish()
+        if self.frames.len() > 1 {
+            self.end_frame();
+        }
+    }
+
+    fn end_frame(&mut self) {
+        let path = self
+            .frames
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(";");
+        let frame = self.frames.pop().unwrap();
+        let elapsed = frame.start.elapsed();
+        let exclusive = elapsed.saturating_sub(frame.children);
+
+        *self.stacks.entry(path).or_default() += exclusive;
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += elapsed;
+        }
+
+        if let Some(stats) = self.functions.get_mut(&frame.name) {
+            stats.exclusive += exclusive;
+            //Only the outermost call of a recursive function counts towards inclusive time
+            if !self.frames.iter().any(|f| f.name == frame.name) {
+                stats.inclusive += elapsed;
+            }
+        }
+    }
+
+    //Close any frames left open and print the report to stderr
+    pub fn finish(&mut self, lex: &Lexer) -> Result<(), String> {
+        while !self.frames.is_empty() {
+            self.end_frame();
+        }
+
+        let mut out = String::new();
+
+        out += "Operators:\n";
+        let mut ops: Vec<(&&str, &u64)> = self.ops.iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (op, count) in ops {
+            out += &format!("{:>12}  {}\n", count, op);
+        }
+
+        //Finding a position's line scans the file up to it, so the positions are sorted and
+        //the file scanned once for all of them
+        let mut hits: Vec<(&usize, &u64)> = self.hits.iter().collect();
+        hits.sort();
+        let mut lines = HashMap::<usize, u64>::new();
+        let (mut line, mut i) = (0, 0);
+        for (pos, count) in hits {
+            while i < *pos {
+                if lex.chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            *lines.entry(line.max(1)).or_insert(0) += count;
+        }
+
+        out += "\nLines:\n";
+        let mut lines: Vec<(&usize, &u64)> = lines.iter().collect();
+        lines.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (line, count) in lines {
+            out += &format!("{:>12}  line {}\n", count, line);
+        }
+
+        out += "\nFunctions:\n";
+        out += &format!(
+            "{:>12}  {:>12}  {:>12}  name\n",
+            "calls", "inclusive", "exclusive"
+        );
+        let mut functions: Vec<(&String, &FnStats)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (name, stats) in functions {
+            out += &format!(
+                "{:>12}  {:>12.3?}  {:>12.3?}  {}\n",
+                stats.calls, stats.inclusive, stats.exclusive, name
+            );
+        }
+
+        out += &format!("\nPeak stack depth: {}\n", self.peak_stack);
+        out += &format!("Peak scope depth: {}\n", self.peak_scopes);
+        eprint!("{}", out);
+
+        if let Some(path) = &self.folded {
+            let mut stacks: Vec<(&String, &Duration)> = self.stacks.iter().collect();
+            stacks.sort();
+
+            let fail = |e: std::io::Error| format!("Cannot write {}: {}", path, e);
+            let mut file = std::fs::File::create(path).map_err(fail)?;
+            for (stack, time) in stacks {
+                writeln!(file, "{} {}", stack, time.as_micros()).map_err(fail)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+/* Runtime for aqua programs compiled to C, mirroring the interpreter's OpStack and VarStore */
+#include <setjmp.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* Not every program uses every part of the runtime */
+#if defined(__GNUC__)
+#define AQ_FN static __attribute__((unused))
+#else
+#define AQ_FN static
+#endif
+
+/* Loop variables are never changed between a '!?' block's setjmp and a longjmp back to it */
+#if defined(__GNUC__) && !defined(__clang__)
+#pragma GCC diagnostic ignored "-Wclobbered"
+#endif
+
+enum { AQ_INT, AQ_BOOL, AQ_STRING };
+
+typedef struct {
+    int type;
+    int32_t i; /* also holds Bools */
+    char *s;
+    size_t len;
+} aq_value;
+
+typedef struct {
+    const char *name;
+    aq_value value;
+    const char *defined; /* where a constant was defined, NULL for a variable */
+} aq_var;
+
+typedef struct {
+    aq_var *vars;
+    size_t len, cap;
+    int block; /* a block's scope, rather than a function call's */
+} aq_scope;
+
+static aq_value *aq_stack;
+static size_t aq_len, aq_cap;
+static aq_scope *aq_scopes;
+static size_t aq_scope_len, aq_scope_cap;
+static int aq_lexical; /* only the current function's and the global scopes can be seen, rather than every caller's */
+static int aq_block_scopes; /* every block gets a scope, rather than only function calls */
+
+/* A '!?' block being run: where to jump on an error, and the state to go back to */
+typedef struct {
+    jmp_buf env;
+    aq_value *stack;
+    size_t len, scopes;
+} aq_try;
+
+static aq_try *aq_tries;
+static size_t aq_try_len, aq_try_cap;
+static aq_value aq_thrown;
+static char *aq_thrown_msg;
+
+AQ_FN aq_value aq_str(const char *s, size_t len);
+
+/* Jump to the innermost handler with the value and its message */
+AQ_FN void aq_raise(aq_value v, const char *msg) {
+    aq_thrown = v;
+    aq_thrown_msg = malloc(strlen(msg) + 1);
+    if (!aq_thrown_msg) exit(1);
+    strcpy(aq_thrown_msg, msg);
+    longjmp(aq_tries[aq_try_len - 1].env, 1);
+}
+
+AQ_FN void aq_error(const char *msg) {
+    if (aq_try_len) aq_raise(aq_str(msg, strlen(msg)), msg);
+
+    fflush(stdout);
+    fprintf(stderr, "%s\n", msg);
+    exit(1);
+}
+
+AQ_FN void *aq_grow(void *p, size_t *cap, size_t size) {
+    *cap = *cap ? *cap * 2 : 16;
+    p = realloc(p, *cap * size);
+    if (!p) aq_error("Out of memory!");
+    return p;
+}
+
+AQ_FN void aq_free(aq_value v) {
+    if (v.type == AQ_STRING) free(v.s);
+}
+
+AQ_FN aq_value aq_int(int32_t i) {
+    aq_value v = {AQ_INT, i, NULL, 0};
+    return v;
+}
+
+AQ_FN aq_value aq_bool(int b) {
+    aq_value v = {AQ_BOOL, b != 0, NULL, 0};
+    return v;
+}
+
+AQ_FN aq_value aq_str(const char *s, size_t len) {
+    aq_value v = {AQ_STRING, 0, malloc(len + 1), len};
+    if (!v.s) aq_error("Out of memory!");
+    memcpy(v.s, s, len);
+    v.s[len] = 0;
+    return v;
+}
+
+AQ_FN aq_value aq_copy(aq_value v) {
+    return v.type == AQ_STRING ? aq_str(v.s, v.len) : v;
+}
+
+/* Append to a String, taking ownership of it */
+AQ_FN aq_value aq_append(aq_value v, const char *s, size_t len) {
+    v.s = realloc(v.s, v.len + len + 1);
+    if (!v.s) aq_error("Out of memory!");
+    memcpy(v.s + v.len, s, len);
+    v.len += len;
+    v.s[v.len] = 0;
+    return v;
+}
+
+AQ_FN const char *aq_bool_str(int32_t b) {
+    return b ? "true" : "false";
+}
+
+AQ_FN void aq_push(aq_value v) {
+    if (aq_len == aq_cap) aq_stack = aq_grow(aq_stack, &aq_cap, sizeof(aq_value));
+    aq_stack[aq_len++] = v;
+}
+
+AQ_FN aq_value aq_pop(void) {
+    if (!aq_len) aq_error("Cannot pop from empty stack!");
+    return aq_stack[--aq_len];
+}
+
+AQ_FN int32_t aq_pop_int(const char *err) {
+    aq_value v = aq_pop();
+    if (v.type != AQ_INT) aq_error(err);
+    return v.i;
+}
+
+AQ_FN aq_value aq_pop_str(const char *err) {
+    aq_value v = aq_pop();
+    if (v.type != AQ_STRING) aq_error(err);
+    return v;
+}
+
+AQ_FN int32_t aq_checked(int64_t r, const char *err) {
+    if (r < INT32_MIN || r > INT32_MAX) aq_error(err);
+    return (int32_t)r;
+}
+
+/* Same rules as Rust's str::trim().parse::<i32>() */
+AQ_FN int32_t aq_parse_int(aq_value v) {
+    const char *s = v.s, *end = v.s + v.len;
+    int64_t n = 0;
+    int neg = 0;
+
+    while (s < end && (*s == ' ' || (*s >= '\t' && *s <= '\r'))) s++;
+    while (end > s && (end[-1] == ' ' || (end[-1] >= '\t' && end[-1] <= '\r'))) end--;
+    if (s < end && (*s == '+' || *s == '-')) neg = *s++ == '-';
+    if (s == end) aq_error("Cannot parse string to int");
+
+    for (; s < end; s++) {
+        if (*s < '0' || *s > '9') aq_error("Cannot parse string to int");
+        n = n * 10 + (*s - '0');
+        if (n > (int64_t)INT32_MAX + 1) aq_error("Cannot parse string to int");
+    }
+
+    return aq_checked(neg ? -n : n, "Cannot parse string to int");
+}
+
+AQ_FN void aq_add(void) {
+    aq_value a = aq_pop(), b;
+    char buf[16];
+
+    switch (a.type) {
+    case AQ_INT:
+        b = aq_pop();
+        if (b.type == AQ_INT) {
+            aq_push(aq_int(aq_checked((int64_t)a.i + b.i, "attempt to add with overflow")));
+        } else if (b.type == AQ_STRING) {
+            aq_push(aq_append(b, buf, (size_t)sprintf(buf, "%d", a.i)));
+        } else {
+            aq_error("Add only implemented for Int and String");
+        }
+        break;
+    case AQ_STRING:
+        b = aq_pop();
+        if (b.type == AQ_INT) {
+            aq_push(aq_int(aq_checked((int64_t)aq_parse_int(a) + b.i, "attempt to add with overflow")));
+        } else if (b.type == AQ_STRING) {
+            aq_push(aq_append(b, a.s, a.len));
+        } else {
+            const char *t = aq_bool_str(b.i);
+            aq_push(aq_append(aq_str(t, strlen(t)), a.s, a.len));
+        }
+        aq_free(a);
+        break;
+    default:
+        b = aq_pop();
+        if (b.type != AQ_STRING) aq_error("Can only append Bools to Strings!");
+        aq_push(aq_append(b, aq_bool_str(a.i), strlen(aq_bool_str(a.i))));
+        break;
+    }
+}
+
+AQ_FN void aq_sub(void) {
+    int32_t i = aq_pop_int("Sub only implemented for int");
+    int32_t i2 = aq_pop_int("Sub only implemented for int");
+    aq_push(aq_int(aq_checked((int64_t)i2 - i, "attempt to subtract with overflow")));
+}
+
+AQ_FN void aq_mul(void) {
+    int32_t i = aq_pop_int("Mul only implemented for int");
+    int32_t i2 = aq_pop_int("Mul only implemented for int");
+    aq_push(aq_int(aq_checked((int64_t)i * i2, "attempt to multiply with overflow")));
+}
+
+AQ_FN void aq_div(void) {
+    int32_t i = aq_pop_int("Mul only implemented for int");
+    int32_t i2 = aq_pop_int("Mul only implemented for int");
+    if (i == 0) aq_error("attempt to divide by zero");
+    aq_push(aq_int(aq_checked((int64_t)i2 / i, "attempt to divide with overflow")));
+}
+
+AQ_FN void aq_equal(int negate) {
+    aq_value a = aq_pop(), b = aq_pop();
+    int eq;
+
+    if (a.type != b.type) {
+        aq_error(a.type == AQ_INT    ? "Can only compare Int with Int!"
+                 : a.type == AQ_BOOL ? "Can only compare Bool with Bool!"
+                                     : "Can only compare String with String!");
+    }
+    if (a.type == AQ_STRING) {
+        eq = a.len == b.len && memcmp(a.s, b.s, a.len) == 0;
+    } else {
+        eq = a.i == b.i;
+    }
+
+    aq_free(a);
+    aq_free(b);
+    aq_push(aq_bool(negate ? !eq : eq));
+}
+
+AQ_FN void aq_less_than(void) {
+    int32_t i = aq_pop_int("Can only compare Ints!");
+    aq_push(aq_bool(aq_pop_int("Can only compare Ints!") < i));
+}
+
+AQ_FN void aq_greater_than(void) {
+    int32_t i = aq_pop_int("Can only compare Ints!");
+    aq_push(aq_bool(aq_pop_int("Can only compare Ints!") > i));
+}
+
+AQ_FN int aq_cond(void) {
+    aq_value v = aq_pop();
+    if (v.type != AQ_BOOL) aq_error("Conditional requires Bool at top of stack!");
+    return v.i;
+}
+
+/* Whether a match case's literal equals the value being matched. Values of different types never match */
+AQ_FN int aq_matches(aq_value v, aq_value c) {
+    int eq = v.type == c.type &&
+             (v.type == AQ_STRING ? v.len == c.len && memcmp(v.s, c.s, v.len) == 0 : v.i == c.i);
+    aq_free(c);
+    return eq;
+}
+
+AQ_FN int aq_while_cond(void) {
+    aq_value v = aq_pop();
+    if (v.type != AQ_BOOL) aq_error("While loop condition must leave a Bool!");
+    return v.i;
+}
+
+AQ_FN void aq_assert(void) {
+    aq_value v = aq_pop();
+    if (v.type != AQ_BOOL) aq_error("Assert requires a Bool!");
+    if (!v.i) aq_error("Assertion failed!");
+}
+
+/* Writes `v` as it would appear in a program, so that 1 and "1" can be told apart */
+AQ_FN aq_value aq_literal(aq_value v) {
+    char buf[16];
+
+    if (v.type == AQ_INT) return aq_str(buf, (size_t)sprintf(buf, "%d", v.i));
+    if (v.type == AQ_BOOL) return aq_str(aq_bool_str(v.i), strlen(aq_bool_str(v.i)));
+    return aq_append(aq_append(aq_str("\"", 1), v.s, v.len), "\"", 1);
+}
+
+/* Values of different types are never equal, rather than an error */
+AQ_FN void aq_assert_eq(void) {
+    aq_value right = aq_pop(), left = aq_pop();
+    int eq = left.type == right.type &&
+             (left.type == AQ_STRING ? left.len == right.len && memcmp(left.s, right.s, left.len) == 0
+                                     : left.i == right.i);
+
+    if (!eq) {
+        aq_value msg = aq_str("Assertion failed: ", 18), l = aq_literal(left), r = aq_literal(right);
+        msg = aq_append(msg, l.s, l.len);
+        msg = aq_append(msg, " != ", 4);
+        msg = aq_append(msg, r.s, r.len);
+        aq_error(msg.s);
+    }
+    aq_free(left);
+    aq_free(right);
+}
+
+AQ_FN void aq_print(void) {
+    if (!aq_len) {
+        printf("_\n");
+        return;
+    }
+
+    aq_value v = aq_stack[aq_len - 1];
+    if (v.type == AQ_INT) printf("%d", v.i);
+    else if (v.type == AQ_BOOL) printf("%s", aq_bool_str(v.i));
+    else fwrite(v.s, 1, v.len, stdout);
+    fflush(stdout);
+}
+
+AQ_FN void aq_silent_pop(void) {
+    aq_free(aq_pop());
+}
+
+AQ_FN void aq_clear(void) {
+    while (aq_len) aq_free(aq_stack[--aq_len]);
+}
+
+AQ_FN void aq_push_scope(int block) {
+    if (aq_scope_len == aq_scope_cap) aq_scopes = aq_grow(aq_scopes, &aq_scope_cap, sizeof(aq_scope));
+    aq_scopes[aq_scope_len].vars = NULL;
+    aq_scopes[aq_scope_len].len = aq_scopes[aq_scope_len].cap = 0;
+    aq_scopes[aq_scope_len].block = block;
+    aq_scope_len++;
+}
+
+/* The scope of a function call */
+AQ_FN void aq_new_scope(void) {
+    aq_push_scope(0);
+}
+
+AQ_FN void aq_new_block_scope(void) {
+    aq_push_scope(1);
+}
+
+AQ_FN void aq_destroy_scope(void) {
+    if (!aq_scope_len) return;
+
+    aq_scope *scope = &aq_scopes[--aq_scope_len];
+    for (size_t i = 0; i < scope->len; i++) aq_free(scope->vars[i].value);
+    free(scope->vars);
+}
+
+AQ_FN void aq_destroy_scopes(size_t n) {
+    while (n--) aq_destroy_scope();
+}
+
+/* Index of the current function's scope, below any of its blocks' */
+AQ_FN size_t aq_frame(void) {
+    size_t s = aq_scope_len - 1;
+    while (s > 0 && aq_scopes[s].block) s--;
+    return s;
+}
+
+/* Whether names can be found in the scope at index `s` */
+AQ_FN int aq_visible(size_t s) {
+    return !aq_lexical || s == 0 || s >= aq_frame();
+}
+
+AQ_FN int aq_has(aq_scope *scope, const char *name) {
+    for (size_t i = 0; i < scope->len; i++) {
+        if (strcmp(scope->vars[i].name, name) == 0) return 1;
+    }
+    return 0;
+}
+
+/* Fails if the name is a constant in a visible scope */
+AQ_FN void aq_writable(const char *name) {
+    for (size_t s = 0; s < aq_scope_len; s++) {
+        if (!aq_visible(s)) continue;
+        aq_scope *scope = &aq_scopes[s];
+        for (size_t i = 0; i < scope->len; i++) {
+            if (scope->vars[i].defined && strcmp(scope->vars[i].name, name) == 0) {
+                char *msg = malloc(strlen(name) + strlen(scope->vars[i].defined) + 64);
+                if (!msg) aq_error("Out of memory!");
+                sprintf(msg, "Cannot assign to constant '%s', defined at %s!", name, scope->vars[i].defined);
+                aq_error(msg);
+            }
+        }
+    }
+}
+
+/* Pop into the scope, as a constant if `defined` is set */
+AQ_FN void aq_bind(aq_scope *scope, const char *name, const char *defined) {
+    aq_writable(name);
+    if (!aq_len) aq_error("Cannot assign from empty stack!");
+
+    aq_value v = aq_pop();
+    for (size_t i = 0; i < scope->len; i++) {
+        if (strcmp(scope->vars[i].name, name) == 0) {
+            aq_free(scope->vars[i].value);
+            scope->vars[i].value = v;
+            scope->vars[i].defined = defined;
+            return;
+        }
+    }
+
+    if (scope->len == scope->cap) scope->vars = aq_grow(scope->vars, &scope->cap, sizeof(aq_var));
+    scope->vars[scope->len].name = name;
+    scope->vars[scope->len].value = v;
+    scope->vars[scope->len].defined = defined;
+    scope->len++;
+}
+
+/* With block scopes, a variable of an enclosing block of the same function is updated rather than shadowed */
+AQ_FN void aq_assign(const char *name) {
+    size_t s = aq_scope_len - 1;
+    if (aq_block_scopes) {
+        for (size_t f = aq_frame(); s > f && !aq_has(&aq_scopes[s], name);) s--;
+        if (!aq_has(&aq_scopes[s], name)) s = aq_scope_len - 1;
+    }
+    aq_bind(&aq_scopes[s], name, NULL);
+}
+
+AQ_FN void aq_constant(const char *name, const char *defined) {
+    aq_bind(&aq_scopes[aq_scope_len - 1], name, defined);
+}
+
+/* Assign in the nearest scope that already has the name */
+AQ_FN void aq_assign_outer(const char *name) {
+    aq_writable(name);
+    if (!aq_len) aq_error("Cannot assign from empty stack!");
+
+    for (size_t s = aq_scope_len; s > 0; s--) {
+        if (!aq_visible(s - 1)) continue;
+        aq_scope *scope = &aq_scopes[s - 1];
+        for (size_t i = 0; i < scope->len; i++) {
+            if (strcmp(scope->vars[i].name, name) == 0) {
+                aq_bind(scope, name, NULL);
+                return;
+            }
+        }
+    }
+
+    aq_free(aq_pop());
+    char *msg = malloc(strlen(name) + 64);
+    if (!msg) aq_error("Out of memory!");
+    sprintf(msg, "Cannot assign to unknown variable '%s'!", name);
+    aq_error(msg);
+}
+
+AQ_FN void aq_assign_global(const char *name) {
+    aq_bind(&aq_scopes[0], name, NULL);
+}
+
+/* Push the variable's value, searching from the innermost scope out. Returns 0 if it does not exist */
+AQ_FN int aq_access(const char *name) {
+    for (size_t s = aq_scope_len; s > 0; s--) {
+        if (!aq_visible(s - 1)) continue;
+        aq_scope *scope = &aq_scopes[s - 1];
+        for (size_t i = 0; i < scope->len; i++) {
+            if (strcmp(scope->vars[i].name, name) == 0) {
+                aq_push(aq_copy(scope->vars[i].value));
+                return 1;
+            }
+        }
+    }
+
+    return 0;
+}
+
+AQ_FN void aq_unknown(const char *name) {
+    char *msg = malloc(strlen(name) + 64);
+    if (!msg) aq_error("Out of memory!");
+    sprintf(msg, "Unknown variable '%s' on level %zu!", name, aq_scope_len);
+    aq_error(msg);
+}
+
+/* Index by character, so multi-byte UTF-8 sequences count once */
+AQ_FN void aq_index(void) {
+    int32_t index = aq_pop_int("Index must be an Int!");
+    aq_value v = aq_pop();
+    size_t i = 0, n = 0;
+
+    if (v.type != AQ_STRING) aq_error("Can only index Strings!");
+    if (index < 0) aq_error("Index out of bounds!");
+
+    while (i < v.len) {
+        size_t start = i++;
+        while (i < v.len && (v.s[i] & 0xC0) == 0x80) i++;
+        if (n++ == (size_t)index) {
+            aq_push(aq_str(v.s + start, i - start));
+            aq_free(v);
+            return;
+        }
+    }
+
+    aq_error("Index out of bounds!");
+}
+
+/* The character of `v` starting at byte `*i`, moving `*i` past it */
+AQ_FN aq_value aq_next_char(aq_value v, size_t *i) {
+    size_t start = (*i)++;
+    while (*i < v.len && (v.s[*i] & 0xC0) == 0x80) (*i)++;
+    return aq_str(v.s + start, *i - start);
+}
+
+AQ_FN void aq_input(void) {
+    aq_value v = aq_str("", 0);
+    int c;
+    char ch;
+
+    while ((c = getchar()) != EOF) {
+        ch = (char)c;
+        v = aq_append(v, &ch, 1);
+        if (c == '\n') break;
+    }
+
+    aq_push(v);
+}
+
+/* Start a '!?' block, keeping a copy of the stack to go back to */
+AQ_FN aq_try *aq_try_push(void) {
+    if (aq_try_len == aq_try_cap) aq_tries = aq_grow(aq_tries, &aq_try_cap, sizeof(aq_try));
+
+    aq_try *t = &aq_tries[aq_try_len++];
+    t->stack = malloc((aq_len ? aq_len : 1) * sizeof(aq_value));
+    if (!t->stack) aq_error("Out of memory!");
+    for (size_t i = 0; i < aq_len; i++) t->stack[i] = aq_copy(aq_stack[i]);
+    t->len = aq_len;
+    t->scopes = aq_scope_len;
+    return t;
+}
+
+/* Leave `n` '!?' blocks without anything having gone wrong */
+AQ_FN void aq_try_drop(size_t n) {
+    while (n--) {
+        aq_try *t = &aq_tries[--aq_try_len];
+        for (size_t i = 0; i < t->len; i++) aq_free(t->stack[i]);
+        free(t->stack);
+    }
+}
+
+AQ_FN void aq_try_pop(void) {
+    aq_try_drop(1);
+}
+
+/* Go back to the state at the start of the failed block, then push the value and message for the handler */
+AQ_FN void aq_catch(void) {
+    aq_try *t = &aq_tries[--aq_try_len];
+
+    aq_clear();
+    free(aq_stack);
+    aq_stack = t->stack;
+    aq_len = aq_cap = t->len;
+    if (!aq_cap) {
+        free(aq_stack);
+        aq_stack = NULL;
+    }
+    while (aq_scope_len > t->scopes) aq_destroy_scope();
+
+    aq_push(aq_thrown);
+    aq_push(aq_str(aq_thrown_msg, strlen(aq_thrown_msg)));
+    free(aq_thrown_msg);
+}
+
+AQ_FN void aq_throw(void) {
+    aq_value v = aq_pop();
+    char buf[16];
+    const char *msg = v.type == AQ_STRING ? v.s : v.type == AQ_BOOL ? aq_bool_str(v.i) : buf;
+
+    if (v.type == AQ_INT) sprintf(buf, "%d", v.i);
+    if (aq_try_len) aq_raise(v, msg);
+
+    fflush(stdout);
+    fprintf(stderr, "Uncaught error: %s\n", msg);
+    exit(1);
+}
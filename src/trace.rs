@@ -0,0 +1,126 @@
+//Execution trace - logs every operation the interpreter runs, with the stack after it, and
+//every scope pushed and popped
+use crate::{Lexer, OpStack};
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+pub struct Tracer {
+    pub out: Box<dyn Write>,
+    pub function: Option<String>, //only trace while inside this function
+    pub limit: Option<usize>,     //only trace the first n steps
+    steps: usize,
+    calls: Vec<String>,       //names of the functions being run, innermost last
+    error: Option<io::Error>, //the first write that failed, after which nothing more is written
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer {
+            out: Box::new(io::stderr()),
+            function: None,
+            limit: None,
+            steps: 0,
+            calls: Vec::<String>::new(),
+            error: None,
+        }
+    }
+
+    fn line(&mut self, args: fmt::Arguments) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", args) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    //Flush the trace, reporting any write that failed along the way
+    pub fn finish(&mut self) -> Result<(), String> {
+        let result = match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        };
+        result.map_err(|e| format!("Failed to write trace: {}", e))
+    }
+
+    fn active(&self) -> bool {
+        if let Some(limit) = self.limit {
+            if self.steps >= limit {
+                return false;
+            }
+        }
+
+        match &self.function {
+            Some(f) => self.calls.contains(f),
+            None => true,
+        }
+    }
+
+    fn location(lex: &Lexer, pos: usize) -> String {
+        let (line, col) = lex.location(pos);
+        format!("{}:{}", line, col)
+    }
+
+    //Called after each operation, with the position of the token that was run
+    pub fn step(&mut self, lex: &Lexer, start: usize, end: usize, stack: &OpStack) {
+        if !self.active() {
+            return;
+        }
+        self.steps += 1;
+
+        let end = end.min(lex.chars.len() - 1);
+        let text: String = lex.chars[start..=end].iter().collect();
+        self.line(format_args!(
+            "{}\t{}\t{:?}",
+            Tracer::location(lex, start),
+            text,
+            stack.stack
+        ));
+    }
+
+    pub fn scope_push(&mut self, lex: &Lexer, function: &str, depth: usize) {
+        self.calls.push(function.to_string());
+        if self.active() {
+            self.line(format_args!(
+                "{}\tscope push '{}' (depth {})",
+                Tracer::location(lex, lex.start),
+                function,
+                depth
+            ));
+        }
+    }
+
+    //A block's own scope, in block-scopes mode
+    pub fn block_push(&mut self, lex: &Lexer, depth: usize) {
+        if self.active() {
+            self.line(format_args!(
+                "{}\tscope push block (depth {})",
+                Tracer::location(lex, lex.start),
+                depth
+            ));
+        }
+    }
+
+    pub fn block_pop(&mut self, lex: &Lexer, depth: usize) {
+        if self.active() {
+            self.line(format_args!(
+                "{}\tscope pop block (depth {})",
+                Tracer::location(lex, lex.start),
+                depth
+            ));
+        }
+    }
+
+    pub fn scope_pop(&mut self, lex: &Lexer, depth: usize) {
+        let active = self.active();
+        let function = self.calls.pop().unwrap_or_default();
+        if active {
+            self.line(format_args!(
+                "{}\tscope pop '{}' (depth {})",
+                Tracer::location(lex, lex.start),
+                function,
+                depth
+            ));
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//Golden-file tests - runs every program in tests/programs and compares the results with the files next to it:
+//  name.aq    the program
+//  name.in    stdin, if any
+//  name.args  flags for the interpreter, if any, separated by whitespace
+//  name.out   expected stdout
+//  name.err   expected error location and message, if the program should fail
+//  name.test  expected output of `aqua test` on the program, if it has test functions
+//  name.check expected report of `aqua check`, if it finds anything
+//  name.log   expected stderr of a program that succeeds, if it writes any, with paths and times left out
+//Each program is also formatted, which must not change what it does, and compiled to C when
+//there is a C compiler, which must print the same and fail the same as the interpreter.
+//Run with AQUA_BLESS=1 to rewrite the .out, .err, .test, .check and .log files from the current behaviour
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+struct Outcome {
+    stdout: String,
+    error: Option<String>,
+    log: String,
+}
+
+fn args(program: &Path) -> Vec<String> {
+    let args = fs::read_to_string(program.with_extension("args")).unwrap_or_default();
+    args.split_whitespace().map(str::to_string).collect()
+}
+
+//Run the command with the program's stdin
+fn output(command: &mut Command, program: &Path) -> Output {
+    let stdin = fs::read(program.with_extension("in")).unwrap_or_default();
+    let mut child = command
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn run(program: &Path) -> Outcome {
+    run_source(program, program)
+}
+
+//Run `source` in place of the program, with the program's stdin and flags
+fn run_source(program: &Path, source: &Path) -> Outcome {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_aqua"));
+    let output = output(command.args(args(program)).arg(source), program);
+
+    //A failing program reports "path:line:col: message", the path is left out as it differs between machines
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim_end();
+    let prefix = format!("{}:", source.display());
+    let (error, log) = match output.status.success() {
+        true => (None, untimed(&output.stderr).replace(&prefix, "")),
+        false => (
+            Some(stderr.strip_prefix(&prefix).unwrap_or(stderr).to_string()),
+            String::new(),
+        ),
+    };
+
+    Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        error,
+        log,
+    }
+}
+
+//The output with durations, which differ between runs, replaced by "<time>". Lines with a
+//duration are padded to line up, so their spacing is left out too
+fn untimed(output: &[u8]) -> String {
+    let time = |word: &str| {
+        let number = word.trim_end_matches(char::is_alphabetic);
+        number.contains('.') && number != word && number.parse::<f64>().is_ok()
+    };
+    let output = String::from_utf8_lossy(output);
+    let lines = output
+        .lines()
+        .map(|line| match line.split_whitespace().any(time) {
+            true => {
+                let words = line.split_whitespace();
+                let words = words.map(|w| if time(w) { "<time>" } else { w });
+                words.collect::<Vec<&str>>().join(" ") + "\n"
+            }
+            false => line.to_string() + "\n",
+        });
+    lines.collect()
+}
+
+//An error without its "line:col: ", for comparing programs laid out differently
+fn message(error: &str) -> &str {
+    error.splitn(3, ':').nth(2).map_or(error, str::trim_start)
+}
+
+//Where a program's files made by the test go, so that runs of it do not share them
+fn scratch(program: &Path, extension: &str) -> PathBuf {
+    let name = program.file_stem().unwrap().to_string_lossy();
+    std::env::temp_dir().join(format!(
+        "aqua-golden-{}-{}.{}",
+        std::process::id(),
+        name,
+        extension
+    ))
+}
+
+//Format a copy of the program and check it does the same as the original. Programs the
+//formatter rejects are left alone
+fn check_format(program: &Path, outcome: &Outcome) -> Option<String> {
+    let copy = scratch(program, "aq");
+    fs::copy(program, &copy).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_aqua"))
+        .arg("fmt")
+        .arg(&copy)
+        .output()
+        .unwrap()
+        .status;
+    let formatted = match status.code() {
+        Some(0) => Some(run_source(program, &copy)),
+        Some(1) => None,
+        _ => return Some(format!("fmt ended with {}", status)),
+    };
+    let _ = fs::remove_file(&copy);
+
+    let formatted = formatted?;
+    if formatted.stdout != outcome.stdout {
+        return Some(format!(
+            "formatted, expected stdout {:?}, got {:?}",
+            outcome.stdout, formatted.stdout
+        ));
+    }
+    let (expected, got) = (outcome.error.as_deref(), formatted.error.as_deref());
+    if expected.map(message) != got.map(message) {
+        return Some(format!(
+            "formatted, expected error {:?}, got {:?}",
+            expected, got
+        ));
+    }
+    None
+}
+
+//The report of `aqua check`, with the path left out like above, or what went wrong running it
+fn run_check(program: &Path) -> Result<String, String> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_aqua"));
+    let modes = args(program).into_iter().filter(|a| a == "--lexical");
+    let output = command
+        .arg("check")
+        .args(modes)
+        .arg(program)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    match output.status.code() {
+        Some(0 | 1) => {
+            let prefix = format!("{}:", program.display());
+            Ok(String::from_utf8_lossy(&output.stdout).replace(&prefix, ""))
+        }
+        _ => Err(format!("check ended with {}", output.status)),
+    }
+}
+
+//Whether every error a command reported is one `aqua check` reports too
+fn rejected(program: &Path, stderr: &[u8], report: &str) -> bool {
+    let prefix = format!("{}:", program.display());
+    let stderr = String::from_utf8_lossy(stderr);
+    let mut lines = stderr.lines().peekable();
+    lines.peek().is_some()
+        && lines.all(|l| report.lines().any(|r| l.strip_prefix(&prefix) == Some(r)))
+}
+
+fn has_cc() -> bool {
+    Command::new("cc")
+        .arg("--version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+//Compile the program to C and check the result prints the same and fails the same as the
+//interpreter. Runtime error messages are the C runtime's own, so only success is compared
+fn check_compile(program: &Path, outcome: &Outcome, report: &str) -> Option<String> {
+    let (c, exe) = (scratch(program, "c"), scratch(program, "exe"));
+    let modes = args(program)
+        .into_iter()
+        .filter(|a| a == "--lexical" || a == "--block-scopes");
+    let compiled = Command::new(env!("CARGO_BIN_EXE_aqua"))
+        .arg("compile")
+        .args(modes)
+        .arg("-o")
+        .arg(&c)
+        .arg(program)
+        .output()
+        .unwrap();
+    let result = match compiled.status.code() {
+        //A program that does not compile must be one the interpreter rejects too, or have an
+        //error `aqua check` reports, which the interpreter may never reach
+        Some(1) if outcome.error.is_some() => None,
+        Some(1) if rejected(program, &compiled.stderr, report) => None,
+        Some(0) => {
+            let built = Command::new("cc")
+                .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-o"])
+                .arg(&exe)
+                .arg(&c)
+                .output()
+                .unwrap();
+            match built.status.success() {
+                false => Some(format!(
+                    "C did not build: {}",
+                    String::from_utf8_lossy(&built.stderr)
+                )),
+                true => {
+                    let output = output(&mut Command::new(&exe), program);
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    if stdout != outcome.stdout {
+                        Some(format!(
+                            "compiled, expected stdout {:?}, got {:?}",
+                            outcome.stdout, stdout
+                        ))
+                    } else if output.status.success() != outcome.error.is_none() {
+                        Some(format!(
+                            "compiled, expected error {:?}, got {}",
+                            outcome.error, output.status
+                        ))
+                    } else {
+                        None
+                    }
+                }
+            }
+        }
+        _ => Some(format!(
+            "compile ended with {}: {}",
+            compiled.status,
+            String::from_utf8_lossy(&compiled.stderr)
+        )),
+    };
+
+    let _ = fs::remove_file(&c);
+    let _ = fs::remove_file(&exe);
+    result
+}
+
+//The report of `aqua test`, with the path left out like above
+fn run_tests(program: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_aqua"))
+        .arg("test")
+        .arg(program)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+
+    let prefix = format!(" {}:", program.display());
+    String::from_utf8_lossy(&output.stdout).replace(&prefix, " ")
+}
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "aq"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+#[test]
+fn golden() {
+    let bless = std::env::var_os("AQUA_BLESS").is_some();
+    let cc = has_cc();
+    let mut failures = Vec::<String>::new();
+
+    for program in programs() {
+        let outcome = run(&program);
+        let (out_path, err_path) = (program.with_extension("out"), program.with_extension("err"));
+        let test_path = program.with_extension("test");
+        let tests = test_path.exists().then(|| run_tests(&program));
+        let check_path = program.with_extension("check");
+        let log_path = program.with_extension("log");
+        let name = program.file_name().unwrap().to_string_lossy();
+        let report = match run_check(&program) {
+            Ok(report) => report,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                String::new()
+            }
+        };
+
+        if bless {
+            if let Some(tests) = &tests {
+                fs::write(&test_path, tests).unwrap();
+            }
+            match report.is_empty() {
+                true => {
+                    let _ = fs::remove_file(&check_path);
+                }
+                false => fs::write(&check_path, &report).unwrap(),
+            }
+            match outcome.log.is_empty() {
+                true => {
+                    let _ = fs::remove_file(&log_path);
+                }
+                false => fs::write(&log_path, &outcome.log).unwrap(),
+            }
+            fs::write(&out_path, &outcome.stdout).unwrap();
+            match &outcome.error {
+                Some(e) => fs::write(&err_path, e.to_string() + "\n").unwrap(),
+                None => {
+                    let _ = fs::remove_file(&err_path);
+                }
+            }
+            continue;
+        }
+
+        let expected = fs::read_to_string(&out_path).unwrap_or_default();
+        if outcome.stdout != expected {
+            failures.push(format!(
+                "{}: expected stdout {:?}, got {:?}",
+                name, expected, outcome.stdout
+            ));
+        }
+
+        let expected = fs::read_to_string(&err_path).ok();
+        let expected = expected.as_deref().map(str::trim_end);
+        if outcome.error.as_deref() != expected {
+            failures.push(format!(
+                "{}: expected error {:?}, got {:?}",
+                name, expected, outcome.error
+            ));
+        }
+
+        if let Some(tests) = tests {
+            let expected = fs::read_to_string(&test_path).unwrap();
+            if tests != expected {
+                failures.push(format!(
+                    "{}: expected test report {:?}, got {:?}",
+                    name, expected, tests
+                ));
+            }
+        }
+
+        let expected = fs::read_to_string(&log_path).unwrap_or_default();
+        if outcome.log != expected {
+            failures.push(format!(
+                "{}: expected stderr {:?}, got {:?}",
+                name, expected, outcome.log
+            ));
+        }
+
+        let expected = fs::read_to_string(&check_path).unwrap_or_default();
+        if report != expected {
+            failures.push(format!(
+                "{}: expected check report {:?}, got {:?}",
+                name, expected, report
+            ));
+        }
+
+        //A dump prints the program instead of running it, so there is nothing to compare with
+        if args(&program).iter().any(|a| a.starts_with("--dump")) {
+            continue;
+        }
+        if let Some(e) = check_format(&program, &outcome) {
+            failures.push(format!("{}: {}", name, e));
+        }
+        if let Some(e) = cc
+            .then(|| check_compile(&program, &outcome, &report))
+            .flatten()
+        {
+            failures.push(format!("{}: {}", name, e));
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!("{} failure(s):\n{}", failures.len(), failures.join("\n"));
+    }
+}
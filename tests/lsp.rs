@@ -0,0 +1,173 @@
+//Language server tests - plays a scripted session with `aqua lsp` over stdio and compares
+//each message it sends back with the one expected
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+const URI: &str = "file:///session.aq";
+
+//A document with a function, a variable, a constant and an error, with a name after a
+//character that takes two UTF-16 code units
+const TEXT: &str = "#double { 2 * }\\n3 double =x\\n5 =!limit\\n\\\"😀\\\" . , nope\\n";
+
+fn frame(body: &str) -> Vec<u8> {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+fn request(id: i64, method: &str, params: &str) -> Vec<u8> {
+    frame(&format!(
+        r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#,
+        id, method, params
+    ))
+}
+
+fn notification(method: &str, params: &str) -> Vec<u8> {
+    frame(&format!(
+        r#"{{"jsonrpc":"2.0","method":"{}","params":{}}}"#,
+        method, params
+    ))
+}
+
+fn at(line: i64, character: i64) -> String {
+    format!(
+        r#"{{"textDocument":{{"uri":"{}"}},"position":{{"line":{},"character":{}}}}}"#,
+        URI, line, character
+    )
+}
+
+//Split what the server wrote into message bodies, checking each one's length
+fn messages(output: &[u8]) -> Vec<String> {
+    let mut output = output;
+    let mut found = Vec::new();
+    while !output.is_empty() {
+        let header_end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let header = std::str::from_utf8(&output[..header_end]).unwrap();
+        let length: usize = header
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = &output[header_end + 4..header_end + 4 + length];
+        found.push(String::from_utf8(body.to_vec()).unwrap());
+        output = &output[header_end + 4 + length..];
+    }
+    found
+}
+
+fn range(start: (i64, i64), end: (i64, i64)) -> String {
+    format!(
+        r#"{{"start":{{"line":{},"character":{}}},"end":{{"line":{},"character":{}}}}}"#,
+        start.0, start.1, end.0, end.1
+    )
+}
+
+#[test]
+fn session() {
+    let mut input = Vec::new();
+    input.extend(request(1, "initialize", "{}"));
+    input.extend(notification("initialized", "{}"));
+    input.extend(notification(
+        "textDocument/didOpen",
+        &format!(
+            r#"{{"textDocument":{{"uri":"{}","languageId":"aqua","version":1,"text":"{}"}}}}"#,
+            URI, TEXT
+        ),
+    ));
+    input.extend(request(2, "textDocument/definition", &at(1, 4)));
+    input.extend(request(3, "textDocument/hover", &at(1, 4)));
+    input.extend(request(4, "textDocument/hover", &at(3, 10)));
+    input.extend(request(5, "textDocument/completion", &at(3, 0)));
+    input.extend(request(
+        6,
+        "textDocument/documentSymbol",
+        &format!(r#"{{"textDocument":{{"uri":"{}"}}}}"#, URI),
+    ));
+    input.extend(request(7, "unknown/method", "{}"));
+    input.extend(request(8, "shutdown", "null"));
+    input.extend(notification("exit", "null"));
+    //Anything after exit is never read
+    input.extend(request(9, "shutdown", "null"));
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aqua"))
+        .arg("lsp")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    let expected = vec![
+        r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"hoverProvider":true,"documentSymbolProvider":true,"completionProvider":{}},"serverInfo":{"name":"aqua"}}}"#.to_string(),
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{{"uri":"{}","diagnostics":[{{"range":{},"severity":1,"source":"aqua","message":"Unknown variable 'nope'!"}}]}}}}"#,
+            URI,
+            range((3, 9), (3, 13))
+        ),
+        format!(
+            r#"{{"jsonrpc":"2.0","id":2,"result":{{"uri":"{}","range":{}}}}}"#,
+            URI,
+            range((0, 0), (0, 7))
+        ),
+        format!(
+            r##"{{"jsonrpc":"2.0","id":3,"result":{{"contents":{{"kind":"plaintext","value":"#double ( Int -- Int )"}},"range":{}}}}}"##,
+            range((1, 2), (1, 8))
+        ),
+        format!(
+            r#"{{"jsonrpc":"2.0","id":4,"result":{{"contents":{{"kind":"plaintext","value":"variable nope"}},"range":{}}}}}"#,
+            range((3, 9), (3, 13))
+        ),
+        r#"{"jsonrpc":"2.0","id":5,"result":[{"label":"x","kind":6},{"label":"limit","kind":21},{"label":"double","kind":3}]}"#.to_string(),
+        format!(
+            r#"{{"jsonrpc":"2.0","id":6,"result":[{{"name":"double","kind":12,"range":{},"selectionRange":{}}},{{"name":"x","kind":13,"range":{},"selectionRange":{}}},{{"name":"limit","kind":14,"range":{},"selectionRange":{}}}]}}"#,
+            range((0, 0), (0, 15)),
+            range((0, 0), (0, 7)),
+            range((1, 9), (1, 11)),
+            range((1, 9), (1, 11)),
+            range((2, 2), (2, 9)),
+            range((2, 2), (2, 9))
+        ),
+        r#"{"jsonrpc":"2.0","id":7,"error":{"code":-32601,"message":"Unknown method unknown/method"}}"#.to_string(),
+        r#"{"jsonrpc":"2.0","id":8,"result":null}"#.to_string(),
+    ];
+    let got = messages(&output.stdout);
+    for (i, message) in expected.iter().enumerate() {
+        assert_eq!(got.get(i), Some(message), "message {}", i + 1);
+    }
+    assert_eq!(got.len(), expected.len());
+}
+
+#[test]
+fn client_going_away() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aqua"))
+        .arg("lsp")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    //Nothing reads the answer, so the server's write fails
+    drop(child.stdout.take());
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&request(1, "initialize", "{}"))
+        .unwrap();
+
+    let status = child.wait().unwrap();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(stderr, "");
+}